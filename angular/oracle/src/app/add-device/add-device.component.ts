@@ -11,6 +11,7 @@ export class AddDeviceComponent implements OnInit {
   form = new FormGroup({
     "name": new FormControl(""),
     "ipv4": new FormControl(""),
+    "hostname": new FormControl(""),
     "snmp": new FormControl(true),
     "snmp_community": new FormControl("")
   });
@@ -34,6 +35,10 @@ export class AddDeviceComponent implements OnInit {
     if (data.ipv4 === "") {
       delete data.ipv4;
     }
+    data.hostname = data.hostname.trim();
+    if (data.hostname === "") {
+      delete data.hostname;
+    }
     data.id = 0;
 
     console.log(data);
@@ -13,6 +13,9 @@ export class DevicesComponent implements OnInit {
   showAdd = false;
   ws: WebSocket
   status: any = {}
+  flapping: any = {}
+  maintenance: any = {}
+  dns: any = {}
   start = 0
 
   add() {
@@ -24,8 +27,11 @@ export class DevicesComponent implements OnInit {
   }
 
   get_status(id) {
-    let status = this.status[id];
-    return status || { status: "Unknown", since: this.start }
+    let status = this.status[id] || { status: "Unknown", since: this.start };
+    if (this.maintenance[id]) {
+      return { status: "Maintenance", since: status.since }
+    }
+    return status
   }
 
   constructor(private modal: NzModalService, private viewContainerRef: ViewContainerRef,
@@ -76,14 +82,36 @@ export class DevicesComponent implements OnInit {
       let events = JSON.parse(event.data);
 
       let status = Object.assign({}, this.status);
+      let flapping = Object.assign({}, this.flapping);
+      let maintenance = Object.assign({}, this.maintenance);
+      let dns = Object.assign({}, this.dns);
 
       for (let event of events) {
         if (event.status) {
-          status[event.id] = { status: event.status[0], since: event.status[1].secs_since_epoch }
+          status[event.id] = {
+            status: event.status[0],
+            since: event.status[1].secs_since_epoch,
+            reason: event.reason
+          }
+        }
+        if (event.flapping !== undefined) {
+          flapping[event.id] = event.flapping
+        }
+        if (event.maintenance !== undefined) {
+          maintenance[event.id] = event.maintenance
+        }
+        if (event.dns && event.dns.status) {
+          dns[event.id] = {
+            status: event.dns.status[0],
+            error: event.dns.error
+          }
         }
       }
 
       this.status = status;
+      this.flapping = flapping;
+      this.maintenance = maintenance;
+      this.dns = dns;
 
     };
     this.ws.onopen = ev => {
@@ -0,0 +1,309 @@
+//! Carries out the commands given on the command line
+
+use crate::cli::{Command, ConfigCommand, DeviceCommand, DeviceFields, Options, UserCommand};
+use crate::devices::{self, DeviceConf, DeviceId};
+use crate::groups;
+use crate::log::Log;
+use crate::notifier;
+use crate::ping::Ping;
+use crate::state::{Configuration, User};
+use crate::storage::{self, Storage};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::io::{self, BufRead};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Arc;
+
+pub async fn run(options: &Options, command: Command, log: Arc<Log>) -> Result<(), String> {
+    let storage = || storage::open(&options.data_dir, options.config_file(), log.clone());
+
+    match command {
+        Command::Device(command) => device(&options.data_dir, &*storage(), command, &log),
+        Command::User(command) => user(&*storage(), command),
+        Command::Config(command) => config(&*storage(), command),
+        Command::Check { ip } => check(ip).await,
+        Command::NotifyTest => notify_test(&*storage(), &log),
+    }
+}
+
+fn apply(conf: &mut DeviceConf, fields: DeviceFields) {
+    let text = |value: String| Some(value).filter(|value| !value.is_empty());
+
+    if let Some(name) = fields.name {
+        conf.name = text(name);
+    }
+    if let Some(hostname) = fields.hostname {
+        conf.hostname = text(hostname);
+    }
+    conf.ipv4 = fields.ipv4.or(conf.ipv4);
+    conf.mac = fields.mac.or(conf.mac);
+    conf.group = fields.group.or(conf.group);
+    if fields.no_group {
+        conf.group = None;
+    }
+    if !fields.tags.is_empty() || fields.clear_tags {
+        conf.tags = fields.tags;
+    }
+    if !fields.parents.is_empty() || fields.clear_parents {
+        conf.parents = fields.parents;
+    }
+    conf.always_on = fields.always_on.unwrap_or(conf.always_on);
+    conf.traceroute = fields.traceroute.unwrap_or(conf.traceroute);
+}
+
+fn validate(
+    conf: &DeviceConf,
+    devices: &[DeviceConf],
+    data_dir: &Path,
+    log: &Log,
+) -> Result<(), String> {
+    if conf.ipv4.is_none() && conf.hostname.is_none() {
+        return Err("Either an IPv4 address or a hostname is required".to_owned());
+    }
+    if let Some(group) = conf.group {
+        if !groups::load(data_dir, log)?.iter().any(|g| g.id == group) {
+            return Err(format!("Unknown group {}", group));
+        }
+    }
+    match devices::parents_error(Some(conf.id), &conf.parents, devices) {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+fn device(
+    data_dir: &Path,
+    storage: &dyn Storage,
+    command: DeviceCommand,
+    log: &Log,
+) -> Result<(), String> {
+    let mut devices = storage.load_devices()?;
+    let save = |devices: &[DeviceConf]| {
+        storage
+            .save_devices(devices)
+            .map_err(|error| format!("Unable to save the devices: {}", error))
+    };
+
+    match command {
+        DeviceCommand::Add(fields) => {
+            let ids = devices::load_ids(data_dir, log)?;
+            let id = devices
+                .iter()
+                .map(|device| device.id.saturating_add(1))
+                .fold(ids.next_device, u32::max);
+            // The last id marks that the ids have run out, as in `Devices::new_device_id`
+            if id == DeviceId::MAX {
+                return Err("No device ids are left".to_owned());
+            }
+            let mut conf = DeviceConf {
+                id,
+                ..Default::default()
+            };
+            apply(&mut conf, fields);
+            validate(&conf, &devices, data_dir, log)?;
+
+            devices.push(conf);
+            let ids = devices::Ids {
+                next_device: id + 1,
+                ..ids
+            };
+            devices::save_ids(data_dir, &ids)
+                .map_err(|error| format!("Unable to save the next device id: {}", error))?;
+            save(&devices)?;
+            println!("{}", id);
+        }
+        DeviceCommand::List { json } => {
+            if json {
+                println!("{}", serde_json::to_string_pretty(&devices).unwrap());
+                return Ok(());
+            }
+
+            println!(
+                "{:>5}  {:<24} {:<15} {:<17} HOSTNAME",
+                "ID", "NAME", "IPV4", "MAC"
+            );
+            for device in devices {
+                println!(
+                    "{:>5}  {:<24} {:<15} {:<17} {}",
+                    device.id,
+                    device.name.unwrap_or_default(),
+                    device.ipv4.map(|ip| ip.to_string()).unwrap_or_default(),
+                    device.mac.map(|mac| mac.to_string()).unwrap_or_default(),
+                    device.hostname.unwrap_or_default()
+                );
+            }
+        }
+        DeviceCommand::Edit { id, fields } => {
+            let index = devices
+                .iter()
+                .position(|device| device.id == id)
+                .ok_or_else(|| format!("No device with id {}", id))?;
+            let mut conf = devices[index].clone();
+            apply(&mut conf, fields);
+            validate(&conf, &devices, data_dir, log)?;
+
+            devices[index] = conf;
+            save(&devices)?;
+        }
+        DeviceCommand::Remove { id } => {
+            let count = devices.len();
+            devices.retain(|device| device.id != id);
+            if devices.len() == count {
+                return Err(format!("No device with id {}", id));
+            }
+            for device in &mut devices {
+                device.parents.retain(|&parent| parent != id);
+            }
+            save(&devices)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Uses `password` or reads it from the first line of stdin
+fn password(password: Option<String>) -> Result<String, String> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            let mut line = String::new();
+            io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .map_err(|error| error.to_string())?;
+            line.trim_end_matches(['\r', '\n']).to_owned()
+        }
+    };
+    if password.is_empty() {
+        return Err("The password is empty".to_owned());
+    }
+    Ok(password)
+}
+
+fn save_config(storage: &dyn Storage, conf: &Configuration) -> Result<(), String> {
+    storage
+        .save_config(conf)
+        .map_err(|error| format!("Unable to save the configuration: {}", error))
+}
+
+fn user(storage: &dyn Storage, command: UserCommand) -> Result<(), String> {
+    let mut conf = storage.load_config()?;
+
+    match command {
+        UserCommand::Add { name, password: p } => {
+            if conf.users.iter().any(|user| user.name == name) {
+                return Err(format!("The user {} already exists", name));
+            }
+            conf.users.push(User {
+                name,
+                password: password(p)?,
+            });
+        }
+        UserCommand::Passwd { name, password: p } => {
+            let user = conf
+                .users
+                .iter_mut()
+                .find(|user| user.name == name)
+                .ok_or_else(|| format!("No user named {}", name))?;
+            user.password = password(p)?;
+        }
+        UserCommand::Remove { name } => {
+            let count = conf.users.len();
+            conf.users.retain(|user| user.name != name);
+            if conf.users.len() == count {
+                return Err(format!("No user named {}", name));
+            }
+        }
+    }
+
+    save_config(storage, &conf)
+}
+
+/// Finds the value at a path of object keys separated by `.`
+fn lookup<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    key.split('.')
+        .try_fold(value, |value, part| value.get(part))
+}
+
+fn config(storage: &dyn Storage, command: ConfigCommand) -> Result<(), String> {
+    let conf = serde_json::to_value(storage.load_config()?).unwrap();
+
+    match command {
+        ConfigCommand::Get { key } => {
+            let value = match &key {
+                Some(key) => {
+                    lookup(&conf, key).ok_or_else(|| format!("Unknown setting {}", key))?
+                }
+                None => &conf,
+            };
+            match value {
+                Value::String(value) => println!("{}", value),
+                value => println!("{}", serde_json::to_string_pretty(value).unwrap()),
+            }
+        }
+        ConfigCommand::Set { key, value } => {
+            let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+
+            let mut updated = conf;
+            let mut target = &mut updated;
+            for part in key.split('.') {
+                // Settings which are unset are created
+                if target.is_null() {
+                    *target = json!({});
+                }
+                target = target
+                    .as_object_mut()
+                    .ok_or_else(|| format!("Unknown setting {}", key))?
+                    .entry(part)
+                    .or_insert(Value::Null);
+            }
+            *target = value.clone();
+
+            let conf: Configuration = serde_json::from_value(updated)
+                .map_err(|error| format!("Invalid configuration: {}", error))?;
+
+            // Unknown settings are dropped when the configuration is read
+            if lookup(&serde_json::to_value(&conf).unwrap(), &key) != Some(&value) {
+                return Err(format!("Unknown setting {}", key));
+            }
+
+            save_config(storage, &conf)?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn check(ip: Ipv4Addr) -> Result<(), String> {
+    match Ping::new().ping(ip).await {
+        Ok(rtt) => {
+            println!("{} answered in {:.1} ms", ip, rtt.as_secs_f64() * 1000.0);
+            Ok(())
+        }
+        Err(error) => Err(format!("{} didn't answer: {}", ip, error)),
+    }
+}
+
+fn notify_test(storage: &dyn Storage, log: &Arc<Log>) -> Result<(), String> {
+    let conf = storage.load_config()?;
+    let receivers = match &conf.smtp {
+        Some(smtp) if !smtp.recievers.is_empty() => smtp.recievers.clone(),
+        _ => return Err("No email receivers are configured".to_owned()),
+    };
+
+    let conf = Arc::new(Mutex::new(conf));
+    let failed = receivers
+        .iter()
+        .filter(|receiver| !notifier::send_test_email(log, &conf, receiver))
+        .count();
+
+    if failed > 0 {
+        return Err(format!(
+            "Unable to send to {} of {} receivers",
+            failed,
+            receivers.len()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+//! Command-line options, which can also be set with environment variables, and commands
+
+use crate::devices::DeviceId;
+use crate::groups::GroupId;
+use crate::mac::MacAddr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "oracle", about = "Monitors devices on the network")]
+pub struct Options {
+    /// Directory with the devices, configuration and other state
+    #[structopt(long, env = "ORACLE_DATA_DIR", default_value = "data")]
+    pub data_dir: PathBuf,
+
+    /// Directory with the web interface
+    #[structopt(long, env = "ORACLE_WEB_DIR", default_value = "web")]
+    pub web_dir: PathBuf,
+
+    /// Port of the web interface, overriding `web_port` in the configuration
+    #[structopt(long, env = "ORACLE_PORT")]
+    pub port: Option<u16>,
+
+    /// Address the web interface listens on
+    #[structopt(long, env = "ORACLE_BIND", default_value = "0.0.0.0")]
+    pub bind: IpAddr,
+
+    /// Configuration file, `config.json` in the data directory by default. With the
+    /// `sqlite` feature it's only read to create the database, which keeps the
+    /// configuration from then on.
+    #[structopt(long, env = "ORACLE_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Log filter such as `info` or `warp=debug`, `RUST_LOG` is used if it's not set
+    #[structopt(long, env = "ORACLE_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Runs the server if no command is given
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Commands working on the data directory. Changes aren't seen by a running server and are
+/// overwritten when it saves, so stop it first.
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Adds, lists, edits and removes devices
+    Device(DeviceCommand),
+    /// Adds and removes users of the web interface and changes their passwords
+    User(UserCommand),
+    /// Reads and changes the configuration
+    Config(ConfigCommand),
+    /// Pings an address once
+    Check { ip: Ipv4Addr },
+    /// Sends a test email to every receiver in the configuration
+    NotifyTest,
+}
+
+// Device fields which can be given on the command line. Doc comments here would replace the
+// help of `device edit`.
+#[derive(Debug, StructOpt)]
+pub struct DeviceFields {
+    /// Name of the device, removed if it's empty
+    #[structopt(long)]
+    pub name: Option<String>,
+    #[structopt(long)]
+    pub ipv4: Option<Ipv4Addr>,
+    /// Hostname resolved to find the address, removed if it's empty
+    #[structopt(long)]
+    pub hostname: Option<String>,
+    #[structopt(long)]
+    pub mac: Option<MacAddr>,
+    #[structopt(long)]
+    pub group: Option<GroupId>,
+    /// Removes the device from its group
+    #[structopt(long, conflicts_with = "group")]
+    pub no_group: bool,
+    /// Replaces the tags, may be repeated
+    #[structopt(long = "tag")]
+    pub tags: Vec<String>,
+    /// Removes the tags
+    #[structopt(long, conflicts_with = "tags")]
+    pub clear_tags: bool,
+    /// Replaces the parent devices, may be repeated
+    #[structopt(long = "parent")]
+    pub parents: Vec<DeviceId>,
+    /// Removes the parent devices
+    #[structopt(long, conflicts_with = "parents")]
+    pub clear_parents: bool,
+    #[structopt(long)]
+    pub always_on: Option<bool>,
+    #[structopt(long)]
+    pub traceroute: Option<bool>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum DeviceCommand {
+    /// Adds a device with an IPv4 address or a hostname
+    Add(DeviceFields),
+    /// Lists the devices
+    List {
+        /// Prints the device configurations as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Changes the given fields of a device
+    Edit {
+        id: DeviceId,
+        #[structopt(flatten)]
+        fields: DeviceFields,
+    },
+    /// Removes a device
+    Remove { id: DeviceId },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum UserCommand {
+    /// Adds a user, reading the password from stdin if it's not given
+    Add {
+        name: String,
+        password: Option<String>,
+    },
+    /// Changes the password of a user, reading it from stdin if it's not given
+    Passwd {
+        name: String,
+        password: Option<String>,
+    },
+    /// Removes a user
+    Remove { name: String },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ConfigCommand {
+    /// Prints the configuration or the value at a path such as `smtp.server`
+    Get { key: Option<String> },
+    /// Sets the value at a path, which is parsed as JSON or used as a string otherwise
+    Set { key: String, value: String },
+}
+
+impl Options {
+    pub fn config_file(&self) -> PathBuf {
+        self.config
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join("config.json"))
+    }
+}
@@ -0,0 +1,333 @@
+use crate::devices::{DeviceChange, DeviceConf, Devices, ServiceStatus};
+use crate::log::{Kind, Log};
+use crate::state::Command;
+use serde_json::json;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::process;
+use tokio::spawn;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{timeout, Duration};
+
+// Limit how much output from a command ends up in the log
+const MAX_OUTPUT: usize = 4096;
+
+impl Command {
+    fn matches(&self, device: &DeviceConf, status: ServiceStatus) -> bool {
+        (self.devices.is_empty() || self.devices.contains(&device.id))
+            && (self.on.is_empty() || self.on.contains(&status))
+    }
+}
+
+fn output_text(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(&output[0..output.len().min(MAX_OUTPUT)]);
+    output.trim_end().to_owned()
+}
+
+async fn run(
+    log: Arc<Log>,
+    command: Command,
+    device: DeviceConf,
+    old: (ServiceStatus, SystemTime),
+    new: (ServiceStatus, SystemTime),
+) {
+    let since = new
+        .1
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let child = process::Command::new(&command.program)
+        .args(&command.args)
+        .env("ORACLE_DEVICE_ID", device.id.to_string())
+        .env(
+            "ORACLE_DEVICE_NAME",
+            device.name.clone().unwrap_or_default(),
+        )
+        .env(
+            "ORACLE_DEVICE_IPV4",
+            device.ipv4.map(|ip| ip.to_string()).unwrap_or_default(),
+        )
+        .env("ORACLE_DEVICE_DESC", device.desc())
+        .env("ORACLE_OLD_STATUS", format!("{:?}", old.0))
+        .env("ORACLE_STATUS", format!("{:?}", new.0))
+        .env("ORACLE_SINCE", since.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(error) => {
+            log.log(
+                Kind::Error,
+                &format!("Unable to run command `{}`\n{}", command.program, error),
+            );
+            return;
+        }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        let input = json!({ "device": device, "old": old, "new": new });
+        // Written separately, as the command may not read its input. The write fails once
+        // the command exits or is killed.
+        spawn(async move {
+            stdin.write_all(input.to_string().as_bytes()).await.ok();
+        });
+    }
+
+    let desc = device.desc();
+
+    // The child is killed if we time out and drop it
+    match timeout(
+        Duration::from_secs(command.timeout),
+        child.wait_with_output(),
+    )
+    .await
+    {
+        Ok(Ok(output)) => {
+            let kind = if output.status.success() {
+                Kind::Note
+            } else {
+                Kind::Error
+            };
+            let mut msg = format!(
+                "Command `{}` for device {} exited with {}",
+                command.program, desc, output.status
+            );
+            for text in [output_text(&output.stdout), output_text(&output.stderr)].iter() {
+                if !text.is_empty() {
+                    msg.push('\n');
+                    msg.push_str(text);
+                }
+            }
+            log.log(kind, &msg);
+        }
+        Ok(Err(error)) => log.log(
+            Kind::Error,
+            &format!(
+                "Command `{}` for device {} failed\n{}",
+                command.program, desc, error
+            ),
+        ),
+        Err(_) => log.log(
+            Kind::Error,
+            &format!(
+                "Command `{}` for device {} timed out after {} seconds",
+                command.program, desc, command.timeout
+            ),
+        ),
+    }
+}
+
+pub async fn runner(
+    devices: Arc<Devices>,
+    log: Arc<Log>,
+    mut receiver: mpsc::Receiver<DeviceChange>,
+) {
+    let mut concurrency = 0;
+    let mut limit = Arc::new(Semaphore::new(0));
+
+    while let Some(change) = receiver.recv().await {
+        let (device, old, new) = match change {
+            DeviceChange::IPv4Status {
+                device,
+                old: Some(old),
+                new: Some(new),
+                ..
+            } => (device, old, new),
+            _ => continue,
+        };
+
+        let device = match devices.device(device) {
+            Some(device) => device.conf.lock().clone(),
+            None => continue,
+        };
+        let commands = {
+            let conf = devices.conf.lock();
+
+            // Commands which are already running keep their permits from the previous limit
+            if conf.command_concurrency.max(1) != concurrency {
+                concurrency = conf.command_concurrency.max(1);
+                limit = Arc::new(Semaphore::new(concurrency));
+            }
+
+            conf.commands.clone()
+        };
+
+        for command in commands {
+            if !command.matches(&device, new.0) {
+                continue;
+            }
+
+            let limit = limit.clone();
+            let log = log.clone();
+            let device = device.clone();
+            spawn(async move {
+                let _permit = limit.acquire_owned().await;
+                run(log, command, device, old, new).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::Entry;
+    use crate::simulator::Network;
+    use crate::tests::{devices, temp_dir};
+    use std::fs;
+    use tokio::sync::broadcast;
+    use tokio::time::delay_for;
+
+    fn command(script: &str, timeout: u64) -> Command {
+        Command {
+            program: "sh".to_owned(),
+            args: vec!["-c".to_owned(), script.to_owned()],
+            devices: Vec::new(),
+            on: Vec::new(),
+            timeout,
+        }
+    }
+
+    fn router() -> DeviceConf {
+        DeviceConf {
+            id: 1,
+            name: Some("router".to_owned()),
+            ipv4: Some("10.0.0.1".parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    /// Starts a runner for `commands` and sends it a change of `device` to Down
+    fn start(
+        commands: Vec<Command>,
+        concurrency: usize,
+        device: DeviceConf,
+    ) -> broadcast::Receiver<Entry> {
+        let devices = devices(&Network::new(0));
+        let id = device.id;
+        devices.add(device);
+        {
+            let mut conf = devices.conf.lock();
+            conf.commands = commands;
+            conf.command_concurrency = concurrency;
+        }
+
+        let entries = devices.log.subscribe();
+        let (mut tx, rx) = mpsc::channel(10);
+        spawn(runner(devices.clone(), devices.log.clone(), rx));
+        let time = SystemTime::now();
+        tx.try_send(DeviceChange::IPv4Status {
+            device: id,
+            old: Some((ServiceStatus::Up, time)),
+            new: Some((ServiceStatus::Down, time)),
+            quality: None,
+            reason: None,
+        })
+        .unwrap();
+        spawn(async move {
+            // Keeps the runner going until the test ends
+            delay_for(Duration::from_secs(60)).await;
+            drop(tx);
+        });
+
+        entries
+    }
+
+    /// Waits for the next `count` log entries about commands
+    async fn logged(entries: &mut broadcast::Receiver<Entry>, count: usize) -> Vec<Entry> {
+        let mut logged = Vec::new();
+        while logged.len() < count {
+            let entry = timeout(Duration::from_secs(10), entries.recv())
+                .await
+                .expect("no command finished")
+                .unwrap();
+            if entry.msg.starts_with("Command") {
+                logged.push(entry);
+            }
+        }
+        logged
+    }
+
+    #[test]
+    fn matching() {
+        let device = DeviceConf {
+            id: 1,
+            ..Default::default()
+        };
+        let mut command = command("true", 1);
+        assert!(command.matches(&device, ServiceStatus::Down));
+
+        command.on = vec![ServiceStatus::Down];
+        assert!(command.matches(&device, ServiceStatus::Down));
+        assert!(!command.matches(&device, ServiceStatus::Up));
+
+        command.devices = vec![2];
+        assert!(!command.matches(&device, ServiceStatus::Down));
+        command.devices = vec![1, 2];
+        assert!(command.matches(&device, ServiceStatus::Down));
+    }
+
+    #[tokio::test]
+    async fn environment_and_input() {
+        let script = "echo $ORACLE_DEVICE_ID $ORACLE_OLD_STATUS $ORACLE_STATUS; \
+                      grep -o '\"name\":\"router\"'; exit 3";
+        let mut entries = start(vec![command(script, 10)], 1, router());
+
+        let entry = &logged(&mut entries, 1).await[0];
+        assert!(matches!(entry.kind, Kind::Error));
+        assert!(entry.msg.contains("exit status: 3"), "{}", entry.msg);
+        assert!(
+            entry.msg.ends_with("\n1 Up Down\n\"name\":\"router\""),
+            "{}",
+            entry.msg
+        );
+    }
+
+    #[tokio::test]
+    async fn killed_after_timeout() {
+        let dir = temp_dir("command-timeout");
+        let file = dir.join("finished");
+        let script = format!("sleep 2; touch {}", file.display());
+        // More input than fits in a pipe, which the command never reads
+        let device = DeviceConf {
+            tags: vec!["x".repeat(1 << 20)],
+            ..router()
+        };
+        let mut entries = start(vec![command(&script, 1)], 1, device);
+
+        let entry = &logged(&mut entries, 1).await[0];
+        assert!(
+            entry.msg.contains("timed out after 1 seconds"),
+            "{}",
+            entry.msg
+        );
+        delay_for(Duration::from_secs(2)).await;
+        assert!(!file.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit() {
+        let dir = temp_dir("command-concurrency");
+        // `mkdir` fails if another command is running
+        let script = format!(
+            "mkdir {0}/running || touch {0}/overlap; sleep 0.2; rmdir {0}/running",
+            dir.display()
+        );
+        let mut entries = start(vec![command(&script, 10); 3], 1, router());
+
+        let entries = logged(&mut entries, 3).await;
+        assert!(entries.iter().all(|entry| matches!(entry.kind, Kind::Note)));
+        assert!(!dir.join("overlap").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,20 +1,40 @@
-use crate::state::Conf;
-use crate::{log::Kind, log::Log, ping::Ping};
+use crate::state::{Burst, Conf, FlapDetection};
 use crate::{
+    command,
+    discovery::Discovery,
+    dns::{self, DnsCheck},
+    groups::{self, Group, GroupId},
+    history::History,
+    hostname, hosts,
+    mac::MacAddr,
+    maintenance::{self, Maintenance, MaintenanceId},
+    mdns,
     monitor::{self, CancelToken},
-    notifier,
+    notifier, passive, persist,
+    storage::Storage,
+    traceroute::{self, Trace},
+    wol,
+};
+use crate::{
+    log::Kind,
+    log::Log,
+    ping::{Ping, PingError},
 };
 use futures::{SinkExt, StreamExt};
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use serde_json::{self, json};
-use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::SystemTime;
-use std::{fs, time::Instant};
+use std::time::{Duration, SystemTime};
 use tokio::{
     spawn,
     sync::{broadcast, mpsc},
+    time::Instant,
 };
 use warp::ws;
 use warp::{filters::BoxedFilter, Filter, Reply};
@@ -26,16 +46,64 @@ pub struct DeviceConf {
     pub id: DeviceId,
     pub name: Option<String>,
     pub ipv4: Option<Ipv4Addr>,
+    /// Resolved periodically, replacing `ipv4` with the first IPv4 address found
+    #[serde(default)]
+    pub hostname: Option<String>,
     #[serde(default)]
     pub snmp: bool,
     #[serde(default)]
     pub snmp_community: Option<String>,
+    #[serde(default)]
+    pub mac: Option<MacAddr>,
+    /// Send a wake-on-LAN packet when the device goes down
+    #[serde(default)]
+    pub always_on: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub group: Option<GroupId>,
+    /// Devices this device is reached through
+    #[serde(default)]
+    pub parents: Vec<DeviceId>,
+    /// Overrides the global flap detection settings
+    #[serde(default)]
+    pub flap_detection: Option<FlapDetection>,
+    #[serde(default)]
+    pub degraded: Option<DegradedThresholds>,
+    /// Overrides the global burst settings
+    #[serde(default)]
+    pub burst: Option<Burst>,
+    /// Run traceroutes periodically
+    #[serde(default)]
+    pub traceroute: bool,
+    /// Checks that the device answers DNS queries
+    #[serde(default)]
+    pub dns: Option<DnsCheck>,
+}
+
+/// The device is degraded when either threshold is exceeded over the last `probes` pings
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DegradedThresholds {
+    /// Average round-trip time in milliseconds
+    #[serde(default)]
+    pub rtt: Option<f64>,
+    /// Percentage of lost pings
+    #[serde(default)]
+    pub loss: Option<f64>,
+    #[serde(default = "default_probes")]
+    pub probes: usize,
+}
+
+fn default_probes() -> usize {
+    10
 }
 
 impl DeviceConf {
     pub fn desc(&self) -> String {
         if let Some(name) = &self.name {
             name.clone()
+        } else if let Some(hostname) = &self.hostname {
+            hostname.clone()
         } else if let Some(ipv4) = self.ipv4 {
             ipv4.to_string()
         } else {
@@ -47,73 +115,365 @@ impl DeviceConf {
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
 pub enum ServiceStatus {
     Up,
+    /// Up, but with high latency or packet loss
+    Degraded,
     Down,
+    /// Down because a parent device is down
+    Unreachable,
+    /// The hostname of the device couldn't be resolved
+    DnsFailure,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl ServiceStatus {
+    pub fn is_up(self) -> bool {
+        matches!(self, ServiceStatus::Up | ServiceStatus::Degraded)
+    }
+
+    pub fn severity(self) -> Severity {
+        match self {
+            ServiceStatus::Up => Severity::Info,
+            ServiceStatus::Degraded | ServiceStatus::Unreachable | ServiceStatus::DnsFailure => {
+                Severity::Warning
+            }
+            ServiceStatus::Down => Severity::Critical,
+        }
+    }
+}
+
+/// Ping statistics over recent probes
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct Quality {
+    /// Average round-trip time in milliseconds of answered pings
+    pub rtt: Option<f64>,
+    /// Percentage of lost pings
+    pub loss: f64,
+}
+
+/// Statistics for a burst of pings. Times are in milliseconds.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Round {
+    pub time: SystemTime,
+    pub sent: usize,
+    pub received: usize,
+    /// Percentage of lost pings
+    pub loss: f64,
+    pub min: Option<f64>,
+    pub avg: Option<f64>,
+    pub max: Option<f64>,
+    /// Mean deviation from the average round-trip time
+    pub jitter: Option<f64>,
+}
+
+impl Round {
+    pub fn new(time: SystemTime, probes: &[Option<Duration>]) -> Self {
+        let rtts: Vec<f64> = probes
+            .iter()
+            .filter_map(|probe| probe.map(|rtt| rtt.as_secs_f64() * 1000.0))
+            .collect();
+
+        let sent = probes.len();
+        let received = rtts.len();
+        let loss = if sent > 0 {
+            (sent - received) as f64 * 100.0 / sent as f64
+        } else {
+            0.0
+        };
+
+        let (min, avg, max) = if received > 0 {
+            (
+                Some(rtts.iter().cloned().fold(f64::INFINITY, f64::min)),
+                Some(rtts.iter().sum::<f64>() / received as f64),
+                Some(rtts.iter().cloned().fold(f64::NEG_INFINITY, f64::max)),
+            )
+        } else {
+            (None, None, None)
+        };
+
+        Round {
+            time,
+            sent,
+            received,
+            loss,
+            min,
+            avg,
+            max,
+            jitter: avg
+                .map(|avg| rtts.iter().map(|rtt| (rtt - avg).abs()).sum::<f64>() / received as f64),
+        }
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(rtt) = self.rtt {
+            write!(f, "{:.1} ms average round-trip time and ", rtt)?;
+        }
+        write!(f, "{:.0}% packet loss", self.loss)
+    }
+}
+
+impl Quality {
+    pub fn exceeds(&self, thresholds: &DegradedThresholds) -> bool {
+        let rtt = match (self.rtt, thresholds.rtt) {
+            (Some(rtt), Some(max)) => rtt > max,
+            _ => false,
+        };
+        let loss = thresholds.loss.map(|max| self.loss > max).unwrap_or(false);
+        rtt || loss
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct Service {
     pub status: Option<(ServiceStatus, SystemTime)>,
+    pub quality: Option<Quality>,
+    /// Statistics for the last round of pings
+    pub round: Option<Round>,
+    /// Why the device isn't answering pings
+    pub reason: Option<PingError>,
+    /// The last traceroute to the device
+    pub trace: Option<Trace>,
+    /// Addresses the hostname resolved to
+    pub addresses: Vec<IpAddr>,
+    pub dns_error: Option<String>,
+    /// Why the last DNS check failed
+    pub error: Option<String>,
+    pub flapping: bool,
+    pub maintenance: bool,
     pub monitor: Option<CancelToken>,
+    pub resolver: Option<CancelToken>,
 }
 
 #[derive(Debug)]
 pub struct Device {
     pub conf: Mutex<DeviceConf>,
 
-    // `conf` lock taken before `icmpv4`
+    // `conf` lock taken before `icmpv4` and `dns`
     pub icmpv4: Mutex<Service>,
+    pub dns: Mutex<Service>,
 }
 
 impl Device {
     pub fn new(id: DeviceId) -> Self {
-        let mut conf: DeviceConf = Default::default();
-        conf.id = id;
         Self {
-            conf: Mutex::new(conf),
+            conf: Mutex::new(DeviceConf {
+                id,
+                ..Default::default()
+            }),
             icmpv4: Default::default(),
+            dns: Default::default(),
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum DeviceChange {
-    Added(DeviceId),
-    Removed(DeviceId),
+    // Nothing matches on these yet, the ids are only printed by `Debug`
+    Added(#[allow(dead_code)] DeviceId),
+    Removed(#[allow(dead_code)] DeviceId),
     IPv4Status {
         device: DeviceId,
         old: Option<(ServiceStatus, SystemTime)>,
         new: Option<(ServiceStatus, SystemTime)>,
+        quality: Option<Quality>,
+        reason: Option<PingError>,
+    },
+    IPv4Flapping {
+        device: DeviceId,
+        flapping: bool,
+        status: Option<(ServiceStatus, SystemTime)>,
+        time: SystemTime,
+    },
+    IPv4Maintenance {
+        device: DeviceId,
+        maintenance: bool,
+    },
+    /// A MAC address which wasn't seen before appeared on the network
+    NewHost {
+        mac: MacAddr,
+        ipv4: Ipv4Addr,
+        hostname: Option<String>,
+        time: SystemTime,
+    },
+    /// A known MAC address appeared with a different IP address
+    HostMoved {
+        mac: MacAddr,
+        old: Ipv4Addr,
+        new: Ipv4Addr,
+        time: SystemTime,
+    },
+    DnsStatus {
+        device: DeviceId,
+        old: Option<(ServiceStatus, SystemTime)>,
+        new: Option<(ServiceStatus, SystemTime)>,
+        rtt: Option<f64>,
+        error: Option<String>,
     },
 }
 
 pub struct Devices {
-    pub list: Mutex<Vec<Arc<Device>>>,
+    /// Devices by id. It's locked on its own to look up devices, or after a device's `conf`
+    /// lock in `change` and `remove`, but never before locking a device.
+    registry: Mutex<BTreeMap<DeviceId, Arc<Device>>>,
+    pub groups: Mutex<Vec<Group>>,
+    pub maintenance: Mutex<Vec<Maintenance>>,
+    /// Hosts found on the network which aren't devices yet
+    pub discovery: Discovery,
+    pub history: History,
     pub changes: broadcast::Sender<DeviceChange>,
     pub last_email: Mutex<Option<Instant>>,
+    pub ids: Mutex<Ids>,
     pub notifiers: Mutex<Vec<mpsc::Sender<DeviceChange>>>,
     pub conf: Conf,
     pub ping: Ping,
     pub log: Arc<Log>,
+    pub storage: Arc<dyn Storage>,
+    /// Where state other than the devices and configuration is kept
+    pub data_dir: PathBuf,
 }
 
 impl Devices {
+    /// Creates an empty list of devices without any notifiers
+    pub fn new(
+        conf: Conf,
+        log: Arc<Log>,
+        ping: Ping,
+        storage: Arc<dyn Storage>,
+        data_dir: PathBuf,
+    ) -> Arc<Self> {
+        let (changes, _) = broadcast::channel(1000);
+
+        Arc::new(Devices {
+            registry: Mutex::new(BTreeMap::new()),
+            groups: Mutex::new(Vec::new()),
+            maintenance: Mutex::new(Vec::new()),
+            discovery: Default::default(),
+            history: History::new(storage.clone()),
+            changes,
+            conf,
+            ping,
+            log,
+            last_email: Mutex::new(Some(Instant::now())),
+            ids: Mutex::new(Default::default()),
+            notifiers: Mutex::new(Vec::new()),
+            storage,
+            data_dir,
+        })
+    }
+
     pub async fn notify(self: &Arc<Self>, change: DeviceChange) {
-        let (device, status) = match change.clone() {
+        let notify = match change.clone() {
             DeviceChange::IPv4Status {
+                device,
+                old: Some(old),
+                new: Some(new),
+                quality,
+                reason,
+            } => self.status_changed(device, old, new, quality, reason),
+            DeviceChange::IPv4Flapping {
+                device, flapping, ..
+            } => {
+                let desc = self.desc(device);
+                if flapping {
+                    self.log
+                        .log(Kind::Error, &format!("Device {} is flapping", desc));
+                } else {
+                    self.log.note(&format!("Device {} stopped flapping", desc));
+                }
+                true
+            }
+            DeviceChange::IPv4Maintenance {
+                device,
+                maintenance,
+            } => {
+                let desc = self.desc(device);
+                if maintenance {
+                    self.log
+                        .note(&format!("Device {} entered maintenance", desc));
+                } else {
+                    self.log.note(&format!("Device {} left maintenance", desc));
+                }
+                false
+            }
+            DeviceChange::NewHost {
+                mac,
+                ipv4,
+                hostname,
+                ..
+            } => {
+                let hostname = hostname.map(|name| format!(" ({})", name));
+                self.log.log(
+                    Kind::Error,
+                    &format!(
+                        "New host {} appeared at {}{}",
+                        mac,
+                        ipv4,
+                        hostname.unwrap_or_default()
+                    ),
+                );
+                true
+            }
+            DeviceChange::HostMoved { mac, old, new, .. } => {
+                self.log.log(
+                    Kind::Error,
+                    &format!("Host {} changed address from {} to {}", mac, old, new),
+                );
+                true
+            }
+            DeviceChange::DnsStatus {
                 device,
                 old: Some(_),
                 new: Some(new),
-            } => (device, new),
-            _ => return,
+                rtt,
+                error,
+            } => {
+                let device = match self.device(device) {
+                    Some(device) => device,
+                    None => return,
+                };
+                let (desc, check) = {
+                    let conf = device.conf.lock();
+                    (conf.desc(), conf.dns.as_ref().map(|dns| dns.name.clone()))
+                };
+                let check = check.unwrap_or_default();
+                match new.0 {
+                    ServiceStatus::Degraded => self.log.log(
+                        Kind::Error,
+                        &format!(
+                            "DNS check for {} on device {} is slow with {:.2} ms",
+                            check,
+                            desc,
+                            rtt.unwrap_or_default()
+                        ),
+                    ),
+                    ServiceStatus::Down => self.log.log(
+                        Kind::Error,
+                        &format!(
+                            "DNS check for {} on device {} failed: {}",
+                            check,
+                            desc,
+                            error.unwrap_or_default()
+                        ),
+                    ),
+                    _ => self
+                        .log
+                        .note(&format!("DNS check for {} on device {} is up", check, desc)),
+                }
+                let maintenance = device.icmpv4.lock().maintenance;
+                !maintenance
+            }
+            _ => false,
         };
 
-        let desc = self.device(device).conf.lock().desc();
-
-        match status.0 {
-            ServiceStatus::Up => self.log.log(Kind::Note, &format!("Device {} is up", desc)),
-            ServiceStatus::Down => self
-                .log
-                .log(Kind::Error, &format!("Device {} is down", desc)),
+        if !notify {
+            return;
         }
 
         let notifiers = self.notifiers.lock().clone();
@@ -123,103 +483,378 @@ impl Devices {
         }
     }
 
+    /// Logs a status change and returns whether notifiers should be told about it
+    fn status_changed(
+        self: &Arc<Self>,
+        device: DeviceId,
+        old: (ServiceStatus, SystemTime),
+        status: (ServiceStatus, SystemTime),
+        quality: Option<Quality>,
+        reason: Option<PingError>,
+    ) -> bool {
+        let device = match self.device(device) {
+            Some(device) => device,
+            None => return false,
+        };
+        let (desc, always_on) = {
+            let conf = device.conf.lock();
+            (conf.desc(), conf.always_on && conf.mac.is_some())
+        };
+
+        match status.0 {
+            ServiceStatus::Up => self.log.log(Kind::Note, &format!("Device {} is up", desc)),
+            ServiceStatus::Degraded => self.log.log(
+                Kind::Error,
+                &format!(
+                    "Device {} is degraded with {}",
+                    desc,
+                    quality.unwrap_or_default()
+                ),
+            ),
+            ServiceStatus::Down => {
+                let reason = reason.unwrap_or(PingError::Timeout);
+                self.log
+                    .log(Kind::Error, &format!("Device {} is down: {}", desc, reason));
+
+                if always_on {
+                    wol::wake(self, &device);
+                }
+
+                // Find where the path to the device breaks
+                spawn(traceroute::trace_device(self.clone(), device.clone()));
+            }
+            ServiceStatus::Unreachable => self.log.log(
+                Kind::Note,
+                &format!("Device {} is unreachable as a parent device is down", desc),
+            ),
+            ServiceStatus::DnsFailure => {
+                let error = device.icmpv4.lock().dns_error.clone().unwrap_or_default();
+                self.log.log(
+                    Kind::Error,
+                    &format!(
+                        "Unable to resolve the hostname of device {}: {}",
+                        desc, error
+                    ),
+                )
+            }
+        }
+
+        // Only the parent's root cause is notified
+        if matches!(
+            (old.0, status.0),
+            (_, ServiceStatus::Unreachable)
+                | (ServiceStatus::Unreachable, ServiceStatus::Up)
+                | (ServiceStatus::Unreachable, ServiceStatus::Degraded)
+        ) {
+            return false;
+        }
+
+        // A single notification is sent when flapping starts and stops
+        let icmpv4 = device.icmpv4.lock();
+        !icmpv4.flapping && !icmpv4.maintenance
+    }
+
     pub fn add(self: &Arc<Self>, conf: DeviceConf) {
         let id = conf.id;
-        self.list.lock().push(Arc::new(Device::new(id)));
+        {
+            let mut ids = self.ids.lock();
+            ids.next_device = ids.next_device.max(id.saturating_add(1));
+        }
+        self.registry
+            .lock()
+            .entry(id)
+            .or_insert_with(|| Arc::new(Device::new(id)));
         self.change(id, conf);
         self.changes.send(DeviceChange::Added(id)).ok();
     }
 
-    pub fn remove(self: &Arc<Self>, id: DeviceId) {
-        let index = self.device_index(id);
-        self.change(id, Default::default());
-        index.map(|index| self.list.lock().remove(index));
+    /// Removes a device, returns false if it doesn't exist
+    pub fn remove(self: &Arc<Self>, id: DeviceId) -> bool {
+        let device = match self.device(id) {
+            Some(device) => device,
+            None => return false,
+        };
+
+        {
+            // Holding `conf` means a concurrent `change` either finishes first or sees the
+            // device is gone
+            let mut conf = device.conf.lock();
+            let mut registry = self.registry.lock();
+            match registry.get(&id) {
+                // The device may have been removed and its id reused since it was looked up
+                Some(registered) if Arc::ptr_eq(registered, &device) => (),
+                _ => return false,
+            }
+            registry.remove(&id);
+            drop(registry);
+            self.apply(
+                &device,
+                &mut conf,
+                DeviceConf {
+                    id,
+                    ..Default::default()
+                },
+            );
+        }
+
+        self.history.remove(id);
         self.changes.send(DeviceChange::Removed(id)).ok();
+        true
     }
 
-    pub fn change(self: &Arc<Self>, id: DeviceId, conf: DeviceConf) {
-        let device = self.device(id);
+    /// Starts monitoring `ipv4`, replacing any existing monitor
+    pub fn start_monitor(
+        self: &Arc<Self>,
+        device: &Arc<Device>,
+        icmpv4: &mut Service,
+        ipv4: Ipv4Addr,
+    ) {
+        if let Some(token) = icmpv4.monitor.take() {
+            token.cancel();
+        }
+
+        let token = CancelToken::new();
+        icmpv4.monitor = Some(token.clone());
+        tokio::spawn(monitor::device_monitor(
+            self.clone(),
+            device.clone(),
+            ipv4,
+            token,
+        ));
+    }
+
+    /// Replaces the configuration of a device, returns false if it doesn't exist
+    pub fn change(self: &Arc<Self>, id: DeviceId, conf: DeviceConf) -> bool {
+        self.update(id, |current| *current = conf)
+    }
+
+    /// Changes the configuration of a device with `update` while it's locked, so changes to
+    /// other fields made meanwhile aren't lost. Returns false if the device doesn't exist.
+    pub fn update(self: &Arc<Self>, id: DeviceId, update: impl FnOnce(&mut DeviceConf)) -> bool {
+        let device = match self.device(id) {
+            Some(device) => device,
+            None => return false,
+        };
         let mut device_conf = device.conf.lock();
+
+        // The device may have been removed since it was looked up
+        match self.registry.lock().get(&id) {
+            Some(registered) if Arc::ptr_eq(registered, &device) => (),
+            _ => return false,
+        }
+
+        let mut conf = device_conf.clone();
+        update(&mut conf);
+        self.apply(&device, &mut device_conf, DeviceConf { id, ..conf });
+        true
+    }
+
+    /// Starts and stops the tasks of a device to match its new configuration
+    fn apply(
+        self: &Arc<Self>,
+        device: &Arc<Device>,
+        device_conf: &mut DeviceConf,
+        conf: DeviceConf,
+    ) {
         let old_conf = device_conf.clone();
 
-        if old_conf.ipv4 != conf.ipv4 {
+        if old_conf.hostname != conf.hostname {
             let mut icmpv4 = device.icmpv4.lock();
-            icmpv4.monitor.as_ref().map(|token| token.cancel());
-            icmpv4.monitor = None;
+            if let Some(token) = icmpv4.resolver.take() {
+                token.cancel();
+            }
+            icmpv4.addresses.clear();
+            icmpv4.dns_error = None;
 
-            if let Some(ipv4) = conf.ipv4 {
+            if let Some(hostname) = conf.hostname.clone() {
                 let token = CancelToken::new();
-                icmpv4.monitor = Some(token.clone());
-                tokio::spawn(monitor::device_monitor(
+                icmpv4.resolver = Some(token.clone());
+                tokio::spawn(hostname::resolver(
                     self.clone(),
                     device.clone(),
-                    ipv4,
+                    hostname,
                     token,
                 ));
+            }
+        }
+
+        // A monitor stopped by a resolution failure is restarted when the hostname is removed
+        let stopped = conf.hostname.is_none() && device.icmpv4.lock().monitor.is_none();
+
+        if old_conf.ipv4 != conf.ipv4 || stopped {
+            let mut icmpv4 = device.icmpv4.lock();
+            if let Some(token) = icmpv4.monitor.take() {
+                token.cancel();
+            }
+
+            if let Some(ipv4) = conf.ipv4 {
+                self.start_monitor(device, &mut icmpv4, ipv4);
             } else {
                 icmpv4.status = None;
+                icmpv4.quality = None;
+                icmpv4.round = None;
+                icmpv4.reason = None;
+                icmpv4.trace = None;
+                icmpv4.flapping = false;
+                icmpv4.maintenance = false;
             }
         }
 
-        *device_conf = conf;
-    }
+        if old_conf.dns != conf.dns {
+            let mut dns = device.dns.lock();
+            if let Some(token) = dns.monitor.take() {
+                token.cancel();
+            }
+            dns.status = None;
+            dns.quality = None;
+            dns.error = None;
 
-    pub fn new_device_id(&self) -> DeviceId {
-        // TODO: Race condition. Old Ids may still be referenced by tasks (and browsers)
-        for i in 0..=(u32::MAX) {
-            if self
-                .list
-                .lock()
-                .iter()
-                .find(|d| d.conf.lock().id == i)
-                .is_none()
-            {
-                return i;
+            if let Some(check) = conf.dns.clone() {
+                let token = CancelToken::new();
+                dns.monitor = Some(token.clone());
+                tokio::spawn(dns::monitor(self.clone(), device.clone(), check, token));
             }
         }
-        panic!()
+
+        *device_conf = conf;
     }
 
-    pub fn save(&self) {
-        let confs: Vec<_> = self
-            .list
-            .lock()
+    /// All devices, ordered by id
+    pub fn all(&self) -> Vec<Arc<Device>> {
+        self.registry.lock().values().cloned().collect()
+    }
+
+    /// The configurations of all devices, ordered by id
+    pub fn confs(&self) -> Vec<DeviceConf> {
+        self.all()
             .iter()
             .map(|device| device.conf.lock().clone())
-            .collect();
-        fs::write(
-            "data/devices.json",
-            serde_json::to_string_pretty(&confs).unwrap(),
-        )
-        .unwrap()
+            .collect()
     }
 
-    pub fn device_index(&self, id: DeviceId) -> Option<usize> {
-        self.list
-            .lock()
+    /// Finds the configuration of a device matching `predicate`
+    pub fn find(&self, predicate: impl Fn(&DeviceConf) -> bool) -> Option<DeviceConf> {
+        self.all().iter().find_map(|device| {
+            let conf = device.conf.lock();
+            if predicate(&conf) {
+                Some(conf.clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Counts the devices which are unreachable because of `id`
+    pub fn unreachable_children(&self, id: DeviceId) -> usize {
+        let devices: Vec<_> = self
+            .all()
             .iter()
-            .enumerate()
-            .find(|d| d.1.conf.lock().id == id)
-            .map(|d| d.0)
+            .map(|device| {
+                let conf = device.conf.lock();
+                let status = device.icmpv4.lock().status.map(|s| s.0);
+                (conf.id, conf.parents.clone(), status)
+            })
+            .collect();
+
+        let mut found = vec![id];
+        let mut i = 0;
+        while i < found.len() {
+            let parent = found[i];
+            for (id, parents, status) in &devices {
+                if *status == Some(ServiceStatus::Unreachable)
+                    && parents.contains(&parent)
+                    && !found.contains(id)
+                {
+                    found.push(*id);
+                }
+            }
+            i += 1;
+        }
+        found.len() - 1
     }
 
-    pub fn device(&self, id: DeviceId) -> Arc<Device> {
-        let index = self.device_index(id).unwrap();
-        self.list.lock()[index].clone()
+    /// Allocates an id which was never used before, so tasks and browsers still referring
+    /// to a removed device don't end up with a new one. The last id is never allocated, it
+    /// marks that the ids have run out.
+    pub fn new_device_id(&self) -> io::Result<DeviceId> {
+        let mut ids = self.ids.lock();
+        let id = ids.next_device;
+        if id == DeviceId::MAX {
+            return Err(io::Error::other("No device ids are left"));
+        }
+        ids.next_device = id + 1;
+        save_ids(&self.data_dir, &ids)?;
+        Ok(id)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        self.storage.save_devices(&self.confs())
+    }
+
+    /// Saves the devices from a background task, logging failures
+    pub fn save_logged(&self) {
+        if let Err(error) = self.save() {
+            self.log.log(
+                Kind::Error,
+                &format!("Unable to save the devices: {}", error),
+            );
+        }
+    }
+
+    pub fn device(&self, id: DeviceId) -> Option<Arc<Device>> {
+        self.registry.lock().get(&id).cloned()
+    }
+
+    /// Describes a device, which may have been removed
+    pub fn desc(&self, id: DeviceId) -> String {
+        match self.device(id) {
+            Some(device) => device.conf.lock().desc(),
+            None => format!("<device #{}>", id),
+        }
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct DeviceFilter {
+    tag: Option<String>,
+    group: Option<GroupId>,
+}
+
 pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
     let devices_ = devices.clone();
     let list_devices = warp::path("devices")
         .and(warp::get())
         .and(warp::path::end())
-        .map(move || {
+        .and(warp::query())
+        .map(move |filter: DeviceFilter| {
+            let groups = filter.group.map(|group| devices_.subgroups(group));
             let confs: Vec<_> = devices_
-                .list
-                .lock()
+                .all()
                 .iter()
-                .map(|device| device.conf.lock().clone())
+                .map(|device| {
+                    let conf = device.conf.lock().clone();
+                    let icmpv4 = device.icmpv4.lock();
+                    (conf, icmpv4.round, icmpv4.addresses.clone())
+                })
+                .filter(|(conf, _, _)| {
+                    filter
+                        .tag
+                        .as_ref()
+                        .map(|tag| conf.tags.contains(tag))
+                        .unwrap_or(true)
+                })
+                .filter(|(conf, _, _)| {
+                    groups
+                        .as_ref()
+                        .map(|groups| conf.group.map(|g| groups.contains(&g)).unwrap_or(false))
+                        .unwrap_or(true)
+                })
+                .map(|(conf, round, addresses)| {
+                    let mut device = serde_json::to_value(conf).unwrap();
+                    device["round"] = json!(round);
+                    device["addresses"] = json!(addresses);
+                    device
+                })
                 .collect();
             serde_json::to_string(&confs).unwrap()
         });
@@ -230,9 +865,19 @@ pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
         .and(warp::path::end())
         .and(warp::body::json())
         .map(move |mut device: DeviceConf| {
-            device.id = devices_.new_device_id();
+            if !devices_.group_exists(device.group)
+                || parents_error(None, &device.parents, &devices_.confs()).is_some()
+            {
+                return "error";
+            }
+            device.id = match devices_.new_device_id() {
+                Ok(id) => id,
+                Err(_) => return "error",
+            };
             devices_.add(device);
-            devices_.save();
+            if devices_.save().is_err() {
+                return "error";
+            }
 
             ""
         });
@@ -241,12 +886,21 @@ pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
     let remove = warp::path!("device" / u32)
         .and(warp::delete())
         .map(move |id| {
-            devices_.remove(id);
-            devices_.save();
+            if !devices_.remove(id) || devices_.save().is_err() {
+                return "error";
+            }
 
             ""
         });
 
+    let devices_ = devices.clone();
+    let wake = warp::path!("device" / u32 / "wake")
+        .and(warp::post())
+        .map(move |id| match devices_.device(id) {
+            Some(device) if wol::wake(&devices_, &device) => "",
+            _ => "error",
+        });
+
     let devices_ = devices.clone();
     let status = warp::path!("devices" / "status")
         .and(warp::ws())
@@ -256,17 +910,30 @@ pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
                 let (mut tx, mut rx) = websocket.split();
 
                 let (initial, mut changes) = {
+                    // Subscribe first so no change is missed after the initial statuses
+                    let changes = devices_.changes.subscribe();
                     let initial: Vec<_> = devices_
-                        .list
-                        .lock()
+                        .all()
                         .iter()
                         .map(|device| {
                             let id = device.conf.lock().id;
                             let icmpv4 = device.icmpv4.lock();
-                            json!({"id": id, "status": icmpv4.status})
+                            let dns = device.dns.lock();
+                            json!({
+                                "id": id,
+                                "status": icmpv4.status,
+                                "quality": icmpv4.quality,
+                                "reason": icmpv4.reason.as_ref().map(|reason| reason.to_string()),
+                                "flapping": icmpv4.flapping,
+                                "maintenance": icmpv4.maintenance,
+                                "dns": {
+                                    "status": dns.status,
+                                    "quality": dns.quality,
+                                    "error": dns.error,
+                                },
+                            })
                         })
                         .collect();
-                    let changes = devices_.changes.subscribe();
                     (initial, changes)
                 };
 
@@ -282,15 +949,34 @@ pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
                             }
                         },
                         Ok(change) = changes.recv() => {
-                            let (device, status) = match change {
-                                DeviceChange::IPv4Status { device, old: _, new } => {
-                                    (device, new)
+                            let val = match change {
+                                DeviceChange::IPv4Status { device, old: _, new, quality, reason } => {
+                                    json!([{
+                                        "id": device,
+                                        "status": new,
+                                        "quality": quality,
+                                        "reason": reason.map(|reason| reason.to_string()),
+                                    }])
+                                }
+                                DeviceChange::IPv4Flapping { device, flapping, .. } => {
+                                    json!([{"id": device, "flapping": flapping}])
+                                }
+                                DeviceChange::IPv4Maintenance { device, maintenance } => {
+                                    json!([{"id": device, "maintenance": maintenance}])
+                                }
+                                DeviceChange::DnsStatus { device, old: _, new, rtt, error } => {
+                                    json!([{
+                                        "id": device,
+                                        "dns": {
+                                            "status": new,
+                                            "quality": {"rtt": rtt, "loss": if rtt.is_some() { 0.0 } else { 100.0 }},
+                                            "error": error,
+                                        },
+                                    }])
                                 }
                                 _ => continue,
                             };
 
-                            let val = json!([{"id": device, "status": status}]);
-
                             tx.send(ws::Message::text(
                                 serde_json::to_string(&val).unwrap(),
                             )).await.ok(); // May fail due to the websocket closing
@@ -303,14 +989,75 @@ pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
             })
         });
 
-    list_devices.or(add).or(remove).or(status).boxed()
+    list_devices.or(add).or(remove).or(wake).or(status).boxed()
 }
 
-pub fn load(conf: Conf, log: Arc<Log>) -> Arc<Devices> {
-    let ping = Ping::new();
+/// Why a device can't have `parents`: one doesn't exist in `confs`, is the device itself or
+/// has it as an ancestor. `id` is `None` for a device which isn't added yet, and `confs` are
+/// the other devices as they'll be after the change.
+pub fn parents_error(
+    id: Option<DeviceId>,
+    parents: &[DeviceId],
+    confs: &[DeviceConf],
+) -> Option<String> {
+    for &parent in parents {
+        if Some(parent) == id {
+            return Some(format!("Device {} can't be its own parent", parent));
+        }
+        if !confs.iter().any(|conf| conf.id == parent) {
+            return Some(format!("Unknown parent device {}", parent));
+        }
+    }
 
-    let (changes, _) = broadcast::channel(1000);
+    // A device which isn't added yet can't be anyone's ancestor
+    let id = id?;
+    let mut seen = HashSet::new();
+    let mut ancestors = parents.to_vec();
+    while let Some(ancestor) = ancestors.pop() {
+        if ancestor == id {
+            return Some(format!("The parents of device {} lead back to it", id));
+        }
+        if seen.insert(ancestor) {
+            if let Some(conf) = confs.iter().find(|conf| conf.id == ancestor) {
+                ancestors.extend(&conf.parents);
+            }
+        }
+    }
+    None
+}
 
+/// The next ids handed out, kept so ids of removed devices, groups and maintenance windows
+/// aren't reused
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Ids {
+    #[serde(default)]
+    pub next_device: DeviceId,
+    #[serde(default)]
+    pub next_group: GroupId,
+    #[serde(default)]
+    pub next_maintenance: MaintenanceId,
+}
+
+pub fn save_ids(data_dir: &Path, ids: &Ids) -> io::Result<()> {
+    persist::write(
+        &data_dir.join("ids.json"),
+        &serde_json::to_string_pretty(ids).unwrap(),
+    )
+}
+
+/// Loads the next ids, ids of devices, groups and maintenance windows which still exist are
+/// skipped when they're loaded. Only a missing file starts them from 0, as that could reuse
+/// the ids of removed ones.
+pub fn load_ids(data_dir: &Path, log: &Log) -> Result<Ids, String> {
+    persist::load_optional(&data_dir.join("ids.json"), log).map(Option::unwrap_or_default)
+}
+
+pub fn load(
+    conf: Conf,
+    log: Arc<Log>,
+    storage: Arc<dyn Storage>,
+    data_dir: PathBuf,
+) -> Arc<Devices> {
     let receivers = conf
         .lock()
         .smtp
@@ -318,36 +1065,68 @@ pub fn load(conf: Conf, log: Arc<Log>) -> Arc<Devices> {
         .map(|smtp| smtp.recievers.clone())
         .unwrap_or_default();
 
-    let devices = Arc::new(Devices {
-        list: Mutex::new(Vec::new()),
-        changes,
-        conf: conf.clone(),
-        ping,
-        log: log.clone(),
-        last_email: Mutex::new(Some(Instant::now())),
-        notifiers: Mutex::new(Vec::new()),
-    });
+    let devices = Devices::new(
+        conf.clone(),
+        log.clone(),
+        Ping::new(),
+        storage.clone(),
+        data_dir.clone(),
+    );
+    let groups = groups::load(&data_dir, &log).unwrap();
+    let windows = maintenance::load(&data_dir, &log).unwrap();
+    let ids = load_ids(&data_dir, &log).unwrap();
+    *devices.ids.lock() = Ids {
+        next_group: groups
+            .iter()
+            .map(|group| group.id.saturating_add(1))
+            .fold(ids.next_group, GroupId::max),
+        next_maintenance: windows
+            .iter()
+            .map(|window| window.id.saturating_add(1))
+            .fold(ids.next_maintenance, MaintenanceId::max),
+        ..ids
+    };
+    *devices.groups.lock() = groups;
+    *devices.maintenance.lock() = windows;
+    *devices.discovery.hosts.lock() = hosts::load(&data_dir, &log).unwrap();
 
     for receiver in receivers {
         let (tx, rx) = mpsc::channel(1000);
 
         spawn(notifier::notifier(
-            conf.clone(),
             devices.clone(),
             log.clone(),
             receiver.clone(),
+            notifier::email(conf.clone(), devices.clone(), log.clone(), receiver.clone()),
             rx,
         ));
 
         devices.notifiers.lock().push(tx);
     }
 
-    let list: Vec<DeviceConf> =
-        serde_json::from_str(&fs::read_to_string("data/devices.json").unwrap()).unwrap();
+    let (tx, rx) = mpsc::channel(1000);
+    spawn(command::runner(devices.clone(), log.clone(), rx));
+    devices.notifiers.lock().push(tx);
+
+    let list = storage.load_devices().unwrap();
 
     for device in list {
         devices.add(device);
     }
 
+    // Devices and groups from before ids were persisted move the next ids past them
+    let bumped = *devices.ids.lock();
+    if bumped != ids {
+        if let Err(error) = save_ids(&data_dir, &bumped) {
+            log.log(
+                Kind::Error,
+                &format!("Unable to save the next ids: {}", error),
+            );
+        }
+    }
+
+    spawn(passive::watcher(devices.clone()));
+    spawn(mdns::listener(devices.clone()));
+
     devices
 }
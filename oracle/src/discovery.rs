@@ -0,0 +1,302 @@
+use crate::devices::{DeviceConf, DeviceId, Devices};
+use crate::hosts::KnownHost;
+use crate::mac::MacAddr;
+use crate::mdns::MdnsHost;
+use crate::{dns, passive};
+use futures::{future, stream, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::spawn;
+use tokio::time::{delay_for, Duration};
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+/// An IPv4 network in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+    pub address: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Network {
+    fn mask(self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0)
+    }
+
+    pub fn contains(self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & self.mask() == u32::from(self.address) & self.mask()
+    }
+
+    /// The usable host addresses, leaving out the network and broadcast addresses
+    pub fn hosts(self) -> Vec<Ipv4Addr> {
+        let mask = self.mask();
+        let network = u32::from(self.address) & mask;
+        let broadcast = network | !mask;
+
+        if self.prefix >= 31 {
+            (network..=broadcast).map(Ipv4Addr::from).collect()
+        } else {
+            (network + 1..broadcast).map(Ipv4Addr::from).collect()
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut parts = s.splitn(2, '/');
+        let address = parts.next().ok_or(())?.trim().parse().map_err(|_| ())?;
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.trim().parse().map_err(|_| ())?,
+            None => 32,
+        };
+        if prefix > 32 {
+            return Err(());
+        }
+        Ok(Network { address, prefix })
+    }
+}
+
+/// A host found on the network which may be added as a device
+#[derive(Debug, Serialize, Clone)]
+pub struct Candidate {
+    pub ipv4: Ipv4Addr,
+    pub mac: Option<MacAddr>,
+    pub hostname: Option<String>,
+    /// Service types advertised over mDNS
+    pub services: Vec<String>,
+    /// Round-trip time in milliseconds
+    pub rtt: Option<f64>,
+    pub seen: SystemTime,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Sweep {
+    pub network: String,
+    pub started: SystemTime,
+    pub finished: Option<SystemTime>,
+    /// Addresses pinged so far
+    pub sent: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Discovery {
+    pub sweep: Mutex<Option<Sweep>>,
+    pub candidates: Mutex<Vec<Candidate>>,
+    /// Hosts advertised over mDNS
+    pub mdns: Mutex<Vec<MdnsHost>>,
+    /// Every MAC address seen on the network, `None` until the inventory is created
+    pub hosts: Mutex<Option<Vec<KnownHost>>>,
+}
+
+impl Discovery {
+    /// Adds a candidate or updates the one with the same address, returns whether it is new
+    pub fn found(&self, candidate: Candidate) -> bool {
+        let mut candidates = self.candidates.lock();
+        match candidates.iter_mut().find(|c| c.ipv4 == candidate.ipv4) {
+            Some(existing) => {
+                existing.mac = candidate.mac.or(existing.mac);
+                existing.hostname = candidate.hostname.or_else(|| existing.hostname.take());
+                if !candidate.services.is_empty() {
+                    existing.services = candidate.services;
+                }
+                existing.rtt = candidate.rtt.or(existing.rtt);
+                existing.seen = candidate.seen;
+                false
+            }
+            None => {
+                candidates.push(candidate);
+                true
+            }
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.sweep
+            .lock()
+            .as_ref()
+            .map(|sweep| sweep.finished.is_none())
+            .unwrap_or(false)
+    }
+}
+
+/// The shortest prefix which can be swept, limiting sweeps to 65536 addresses
+const MIN_PREFIX: u8 = 16;
+
+/// The most addresses a sweep waits on at once, for a reply or a reverse lookup
+const MAX_PENDING: usize = 256;
+
+/// Pings an address and adds it as a candidate if it answers
+async fn probe(devices: &Devices, ip: Ipv4Addr) {
+    let mut ping = devices.ping.clone();
+    if let Ok(rtt) = ping.ping(ip).await {
+        let hostname = dns::reverse(ip).await;
+        devices.discovery.found(Candidate {
+            ipv4: ip,
+            mac: None,
+            hostname,
+            services: Vec::new(),
+            rtt: Some(rtt.as_secs_f64() * 1000.0),
+            seen: SystemTime::now(),
+        });
+    }
+}
+
+/// Pings every address in `network`, sending at most `rate` pings per second, and
+/// adds the hosts which answer as candidates
+pub async fn sweep(devices: Arc<Devices>, network: Network, rate: u32) {
+    let interval = Duration::from_secs_f64(1.0 / rate.max(1) as f64);
+
+    // Addresses are only taken from the stream while fewer than `MAX_PENDING` are probed
+    stream::iter(network.hosts())
+        .then(|ip| async move {
+            delay_for(interval).await;
+            ip
+        })
+        .map(|ip| {
+            if let Some(sweep) = devices.discovery.sweep.lock().as_mut() {
+                sweep.sent += 1;
+            }
+            probe(&devices, ip)
+        })
+        .buffer_unordered(MAX_PENDING)
+        .for_each(|()| future::ready(()))
+        .await;
+
+    // The hosts which answered are in the neighbour table now
+    passive::scan(&devices).await;
+
+    let found = devices
+        .discovery
+        .candidates
+        .lock()
+        .iter()
+        .filter(|candidate| network.contains(candidate.ipv4))
+        .count();
+
+    if let Some(sweep) = devices.discovery.sweep.lock().as_mut() {
+        sweep.finished = Some(SystemTime::now());
+    }
+
+    devices.log.note(&format!(
+        "Discovery sweep of {}/{} found {} hosts",
+        network.address, network.prefix, found
+    ));
+}
+
+/// Finds the device using `ip`
+pub fn known(devices: &Devices, ip: Ipv4Addr) -> Option<DeviceId> {
+    devices
+        .find(|conf| conf.ipv4 == Some(ip))
+        .map(|conf| conf.id)
+}
+
+#[derive(Debug, Deserialize)]
+struct SweepRequest {
+    network: String,
+    /// Pings per second
+    #[serde(default = "default_rate")]
+    rate: u32,
+}
+
+fn default_rate() -> u32 {
+    50
+}
+
+pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
+    let devices_ = devices.clone();
+    let list = warp::path("discovery")
+        .and(warp::get())
+        .and(warp::path::end())
+        .map(move || {
+            let sweep = devices_.discovery.sweep.lock().clone();
+            let candidates = devices_.discovery.candidates.lock().clone();
+            let candidates: Vec<_> = candidates
+                .into_iter()
+                .map(|candidate| {
+                    let device = known(&devices_, candidate.ipv4);
+                    let mut candidate = serde_json::to_value(candidate).unwrap();
+                    candidate["device"] = json!(device);
+                    candidate
+                })
+                .collect();
+            serde_json::to_string(&json!({
+                "sweep": sweep,
+                "candidates": candidates,
+            }))
+            .unwrap()
+        });
+
+    let devices_ = devices.clone();
+    let start = warp::path!("discovery" / "sweep")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(move |request: SweepRequest| {
+            let network: Network = match request.network.parse() {
+                Ok(network) => network,
+                Err(_) => return "error",
+            };
+            if network.prefix < MIN_PREFIX || devices_.discovery.running() {
+                return "error";
+            }
+
+            *devices_.discovery.sweep.lock() = Some(Sweep {
+                network: request.network,
+                started: SystemTime::now(),
+                finished: None,
+                sent: 0,
+                total: network.hosts().len(),
+            });
+            spawn(sweep(devices_.clone(), network, request.rate));
+
+            ""
+        });
+
+    let devices_ = devices.clone();
+    let accept = warp::path!("discovery" / "accept")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(move |addresses: Vec<Ipv4Addr>| {
+            let candidates = devices_.discovery.candidates.lock().clone();
+
+            for ip in addresses {
+                let candidate = match candidates.iter().find(|c| c.ipv4 == ip) {
+                    Some(candidate) => candidate,
+                    None => continue,
+                };
+                if known(&devices_, ip).is_some() {
+                    continue;
+                }
+                let id = match devices_.new_device_id() {
+                    Ok(id) => id,
+                    Err(_) => return "error",
+                };
+
+                devices_.add(DeviceConf {
+                    id,
+                    name: candidate.hostname.clone(),
+                    ipv4: Some(ip),
+                    mac: candidate.mac,
+                    ..Default::default()
+                });
+            }
+            if devices_.save().is_err() {
+                return "error";
+            }
+
+            ""
+        });
+
+    let devices_ = devices.clone();
+    let mdns = warp::path!("discovery" / "mdns")
+        .and(warp::get())
+        .map(move || serde_json::to_string(&*devices_.discovery.mdns.lock()).unwrap());
+
+    list.or(start).or(accept).or(mdns).boxed()
+}
@@ -0,0 +1,697 @@
+use crate::devices::{Device, DeviceChange, Devices, Quality, ServiceStatus};
+use crate::monitor::CancelToken;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::prelude::*;
+use tokio::time::{delay_for, timeout, Duration, Instant};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RecordType {
+    #[default]
+    A,
+    Ns,
+    Cname,
+    Soa,
+    Ptr,
+    Mx,
+    Txt,
+    Aaaa,
+    Srv,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Ns => 2,
+            RecordType::Cname => 5,
+            RecordType::Soa => 6,
+            RecordType::Ptr => 12,
+            RecordType::Mx => 15,
+            RecordType::Txt => 16,
+            RecordType::Aaaa => 28,
+            RecordType::Srv => 33,
+        }
+    }
+}
+
+/// Checks that a DNS server answers a query
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DnsCheck {
+    /// The name to look up
+    pub name: String,
+    #[serde(default)]
+    pub record_type: RecordType,
+    /// The server to query, the address of the device if not set
+    #[serde(default)]
+    pub server: Option<IpAddr>,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Query over TCP instead of UDP
+    #[serde(default)]
+    pub tcp: bool,
+    /// The expected response code, 0 is NOERROR
+    #[serde(default)]
+    pub rcode: u8,
+    /// Answers which must be present. Addresses for A and AAAA records and names for
+    /// NS, CNAME, PTR, MX and SRV records.
+    #[serde(default)]
+    pub expected: Vec<String>,
+    /// The check is degraded when the response time in milliseconds exceeds this
+    #[serde(default)]
+    pub rtt: Option<f64>,
+    /// Seconds to wait for a response
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Seconds between checks
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_port() -> u16 {
+    53
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub name: String,
+    pub record_type: u16,
+    /// Addresses for A and AAAA records, names for NS, CNAME, PTR, MX and SRV records,
+    /// text for TXT records and hex for the rest
+    pub data: String,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub id: u16,
+    truncated: bool,
+    rcode: u8,
+    pub answers: Vec<Record>,
+    /// Records from the authority and additional sections
+    pub additional: Vec<Record>,
+}
+
+fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".to_owned(),
+        1 => "FORMERR".to_owned(),
+        2 => "SERVFAIL".to_owned(),
+        3 => "NXDOMAIN".to_owned(),
+        4 => "NOTIMP".to_owned(),
+        5 => "REFUSED".to_owned(),
+        rcode => format!("RCODE {}", rcode),
+    }
+}
+
+pub fn encode_query(id: u16, name: &str, record_type: RecordType) -> Result<Vec<u8>, String> {
+    const RECURSION_DESIRED: u16 = 0x0100;
+    const CLASS_IN: u16 = 1;
+
+    let mut message = Vec::new();
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&RECURSION_DESIRED.to_be_bytes());
+    message.extend_from_slice(&1u16.to_be_bytes()); // Questions
+    message.extend_from_slice(&[0; 6]); // Answer, authority and additional records
+
+    let name = name.trim_end_matches('.');
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(format!("Invalid name `{}`", name));
+            }
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+    }
+    message.push(0);
+
+    message.extend_from_slice(&record_type.code().to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+fn read_u16(message: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *message.get(pos)?,
+        *message.get(pos + 1)?,
+    ]))
+}
+
+/// Reads a possibly compressed name starting at `pos`, which is moved past it
+fn read_name(message: &[u8], pos: &mut usize) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut current = *pos;
+    let mut jumps = 0;
+
+    loop {
+        let len = *message.get(current)? as usize;
+        if len & 0xC0 == 0xC0 {
+            let pointer = (read_u16(message, current)? & 0x3FFF) as usize;
+            if jumps == 0 {
+                *pos = current + 2;
+            }
+            jumps += 1;
+            if jumps > 64 {
+                return None;
+            }
+            current = pointer;
+        } else if len == 0 {
+            if jumps == 0 {
+                *pos = current + 1;
+            }
+            return Some(labels.join("."));
+        } else {
+            let label = message.get(current + 1..current + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            current += 1 + len;
+        }
+    }
+}
+
+fn read_record(message: &[u8], pos: &mut usize) -> Option<Record> {
+    let name = read_name(message, pos)?;
+    let record_type = read_u16(message, *pos)?;
+    let len = read_u16(message, *pos + 8)? as usize;
+    let start = *pos + 10;
+    let data = message.get(start..start + len)?;
+
+    let data = match record_type {
+        1 if len == 4 => Ipv4Addr::new(data[0], data[1], data[2], data[3]).to_string(),
+        28 if len == 16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(data);
+            Ipv6Addr::from(octets).to_string()
+        }
+        2 | 5 | 12 => read_name(message, &mut start.clone())?,
+        // The name follows the preference of MX and the priority, weight and port of SRV
+        15 if len > 2 => read_name(message, &mut (start + 2))?,
+        33 if len > 6 => read_name(message, &mut (start + 6))?,
+        16 => {
+            let mut text = String::new();
+            let mut i = 0;
+            while i < data.len() {
+                let part = data.get(i + 1..i + 1 + data[i] as usize)?;
+                text.push_str(&String::from_utf8_lossy(part));
+                i += 1 + data[i] as usize;
+            }
+            text
+        }
+        _ => data.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    };
+
+    *pos = start + len;
+    Some(Record {
+        name,
+        record_type,
+        data,
+    })
+}
+
+pub fn parse_response(message: &[u8]) -> Option<Response> {
+    let id = read_u16(message, 0)?;
+    let flags = read_u16(message, 2)?;
+    let questions = read_u16(message, 4)?;
+    let answer_count = read_u16(message, 6)?;
+    let additional_count = read_u16(message, 8)? as usize + read_u16(message, 10)? as usize;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        read_name(message, &mut pos)?;
+        pos += 4;
+    }
+
+    let answers = (0..answer_count)
+        .map(|_| read_record(message, &mut pos))
+        .collect::<Option<_>>()?;
+    let additional = (0..additional_count)
+        .map(|_| read_record(message, &mut pos))
+        .collect::<Option<_>>()?;
+
+    Some(Response {
+        id,
+        truncated: flags & 0x0200 != 0,
+        rcode: (flags & 0xF) as u8,
+        answers,
+        additional,
+    })
+}
+
+async fn query_udp(server: SocketAddr, id: u16, query: &[u8]) -> Result<Response, String> {
+    let local: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let mut socket = UdpSocket::bind(local)
+        .await
+        .map_err(|error| error.to_string())?;
+    socket
+        .connect(server)
+        .await
+        .map_err(|error| error.to_string())?;
+    socket
+        .send(query)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    let mut buffer = [0; 4096];
+    loop {
+        let size = socket
+            .recv(&mut buffer)
+            .await
+            .map_err(|error| error.to_string())?;
+        match parse_response(&buffer[0..size]) {
+            Some(response) if response.id == id => return Ok(response),
+            _ => (),
+        }
+    }
+}
+
+async fn query_tcp(server: SocketAddr, id: u16, query: &[u8]) -> Result<Response, String> {
+    let mut stream = TcpStream::connect(server)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    // Messages over TCP are prefixed by their length
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+    stream
+        .write_all(&message)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    let mut len = [0; 2];
+    stream
+        .read_exact(&mut len)
+        .await
+        .map_err(|error| error.to_string())?;
+    let mut buffer = vec![0; u16::from_be_bytes(len) as usize];
+    stream
+        .read_exact(&mut buffer)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    match parse_response(&buffer) {
+        Some(response) if response.id == id => Ok(response),
+        _ => Err("Invalid response".to_owned()),
+    }
+}
+
+fn normalize(answer: &str) -> String {
+    match answer.parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => answer.trim_end_matches('.').to_lowercase(),
+    }
+}
+
+/// Runs the check against `server` and returns the response time
+pub async fn check(check: &DnsCheck, server: SocketAddr) -> Result<Duration, String> {
+    let id = rand::random();
+    let query = encode_query(id, &check.name, check.record_type)?;
+    let start = Instant::now();
+
+    let exchange = async {
+        if check.tcp {
+            return query_tcp(server, id, &query).await;
+        }
+        let response = query_udp(server, id, &query).await?;
+        if response.truncated {
+            query_tcp(server, id, &query).await
+        } else {
+            Ok(response)
+        }
+    };
+
+    let response = timeout(Duration::from_secs(check.timeout), exchange)
+        .await
+        .map_err(|_| "No response".to_owned())??;
+    let rtt = Instant::now().saturating_duration_since(start);
+
+    if response.rcode != check.rcode {
+        return Err(format!(
+            "Expected {} but got {}",
+            rcode_name(check.rcode),
+            rcode_name(response.rcode)
+        ));
+    }
+
+    let answers: Vec<String> = response
+        .answers
+        .iter()
+        .map(|answer| normalize(&answer.data))
+        .collect();
+    let missing: Vec<&str> = check
+        .expected
+        .iter()
+        .filter(|expected| !answers.contains(&normalize(expected)))
+        .map(|expected| expected.as_str())
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(format!("Missing expected answers {}", missing.join(", ")));
+    }
+
+    Ok(rtt)
+}
+
+/// The first nameserver in `/etc/resolv.conf`
+fn system_nameserver() -> Option<IpAddr> {
+    fs::read_to_string("/etc/resolv.conf")
+        .ok()?
+        .lines()
+        .find_map(|line| {
+            let mut parts = line.split_whitespace();
+            if parts.next()? == "nameserver" {
+                parts.next()?.parse().ok()
+            } else {
+                None
+            }
+        })
+}
+
+/// Looks up the name of an address with a PTR query to the system nameserver
+pub async fn reverse(ip: Ipv4Addr) -> Option<String> {
+    let server = SocketAddr::new(system_nameserver()?, 53);
+    let [a, b, c, d] = ip.octets();
+    let name = format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a);
+
+    let id = rand::random();
+    let query = encode_query(id, &name, RecordType::Ptr).ok()?;
+    let response = timeout(Duration::from_secs(2), query_udp(server, id, &query))
+        .await
+        .ok()?
+        .ok()?;
+    response
+        .answers
+        .into_iter()
+        .find(|answer| answer.record_type == 12)
+        .map(|answer| answer.data)
+}
+
+pub async fn monitor(
+    devices: Arc<Devices>,
+    device: Arc<Device>,
+    conf: DnsCheck,
+    cancel: CancelToken,
+) {
+    let id = device.conf.lock().id;
+    let mut status = device.dns.lock().status;
+
+    loop {
+        let server = conf
+            .server
+            .or_else(|| device.conf.lock().ipv4.map(IpAddr::V4));
+
+        let mut result = Err("No server address".to_owned());
+        if let Some(server) = server {
+            // Try 3 times before registering the check as down
+            for _ in 0..3i32 {
+                result = check(&conf, SocketAddr::new(server, conf.port)).await;
+                if result.is_ok() {
+                    break;
+                }
+            }
+        }
+
+        if cancel.cancelled() {
+            break;
+        }
+
+        let rtt = result.as_ref().ok().map(|rtt| rtt.as_secs_f64() * 1000.0);
+        let new_status = match (rtt, conf.rtt) {
+            (Some(rtt), Some(max)) if rtt > max => ServiceStatus::Degraded,
+            (Some(_), _) => ServiceStatus::Up,
+            (None, _) => ServiceStatus::Down,
+        };
+        let error = result.err();
+        let changed = status.map(|s| s.0) != Some(new_status);
+
+        let change = {
+            let mut dns = device.dns.lock();
+
+            // Check that we're not cancelled in the lock, so we have permission to update the device
+            if cancel.cancelled() {
+                break;
+            }
+
+            dns.quality = Some(Quality {
+                rtt,
+                loss: if rtt.is_some() { 0.0 } else { 100.0 },
+            });
+            dns.error = error.clone();
+
+            if changed {
+                let new = Some((new_status, SystemTime::now()));
+                dns.status = new;
+                let change = DeviceChange::DnsStatus {
+                    device: id,
+                    old: status,
+                    new,
+                    rtt,
+                    error,
+                };
+                devices.changes.send(change.clone()).ok();
+                status = new;
+                Some(change)
+            } else {
+                None
+            }
+        };
+
+        if let Some(change) = change {
+            devices.notify(change).await;
+        }
+
+        delay_for(Duration::from_secs(conf.interval.max(1))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: [u8; 12] = [0x1a, 0x2b, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    /// `example.com` as the question name at offset 12, followed by type A and class IN
+    const QUESTION: [u8; 17] = [
+        7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1,
+    ];
+
+    /// A pointer to the question name
+    const EXAMPLE: [u8; 2] = [0xc0, 12];
+
+    fn record(name: &[u8], record_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut record = name.to_vec();
+        record.extend_from_slice(&record_type.to_be_bytes());
+        record.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]);
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    /// A response to the question with `answers`
+    fn answered(flags: u16, answers: &[Vec<u8>]) -> Vec<u8> {
+        let mut message = HEADER.to_vec();
+        message[2..4].copy_from_slice(&flags.to_be_bytes());
+        message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        message.extend_from_slice(&QUESTION);
+        for answer in answers {
+            message.extend_from_slice(answer);
+        }
+        message
+    }
+
+    fn data(message: &[u8]) -> Vec<String> {
+        let response = parse_response(message).unwrap();
+        response
+            .answers
+            .into_iter()
+            .map(|record| record.data)
+            .collect()
+    }
+
+    #[test]
+    fn query() {
+        let mut expected = HEADER.to_vec();
+        expected[2] = 0x01;
+        expected[3] = 0;
+        expected.extend_from_slice(&QUESTION);
+        assert_eq!(
+            encode_query(0x1a2b, "example.com.", RecordType::A),
+            Ok(expected)
+        );
+
+        let root = encode_query(1, ".", RecordType::Ns).unwrap();
+        assert_eq!(&root[12..], &[0, 0, 2, 0, 1]);
+
+        assert!(encode_query(1, "example..com", RecordType::A).is_err());
+        assert!(encode_query(1, &"a".repeat(64), RecordType::A).is_err());
+        assert!(encode_query(1, &"a".repeat(63), RecordType::A).is_ok());
+    }
+
+    #[test]
+    fn captured_response() {
+        // A response to an A query for example.com with an EDNS OPT record
+        let message = [
+            0x1a, 0x2b, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x65,
+            0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00,
+            0x01, 0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x04, 0x5d,
+            0xb8, 0xd8, 0x22, 0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let response = parse_response(&message).unwrap();
+        assert_eq!(response.id, 0x1a2b);
+        assert_eq!(response.rcode, 0);
+        assert!(!response.truncated);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, "example.com");
+        assert_eq!(response.answers[0].record_type, 1);
+        assert_eq!(response.answers[0].data, "93.184.216.34");
+        assert_eq!(response.additional.len(), 1);
+        assert_eq!(response.additional[0].name, "");
+        assert_eq!(response.additional[0].record_type, 41);
+
+        // Every shorter message is missing part of a record
+        for len in 0..message.len() {
+            assert!(parse_response(&message[..len]).is_none(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn record_data() {
+        let mail = [4, b'm', b'a', b'i', b'l', 0xc0, 12];
+        let mut mx = vec![0, 10];
+        mx.extend_from_slice(&mail);
+        let mut srv = vec![0, 1, 0, 5, 0, 53];
+        srv.extend_from_slice(&mail);
+        let aaaa = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+        let message = answered(
+            0x8180,
+            &[
+                record(&EXAMPLE, 5, &mail),
+                record(&EXAMPLE, 15, &mx),
+                record(&EXAMPLE, 33, &srv),
+                record(&EXAMPLE, 28, &aaaa),
+                record(&EXAMPLE, 16, b"\x05hello\x06 world\x00"),
+                record(&EXAMPLE, 99, &[0xab, 0x01]),
+            ],
+        );
+
+        assert_eq!(
+            data(&message),
+            vec![
+                "mail.example.com",
+                "mail.example.com",
+                "mail.example.com",
+                "2001:db8::1",
+                "hello world",
+                "ab01",
+            ]
+        );
+    }
+
+    #[test]
+    fn name_compression() {
+        // A name ending in a pointer to a name which itself ends in a pointer
+        let mut message = answered(0x8180, &[]);
+        let www = message.len();
+        message.extend_from_slice(&[3, b'w', b'w', b'w', 0xc0, 12]);
+        let mut pos = www;
+        assert_eq!(read_name(&message, &mut pos).unwrap(), "www.example.com");
+        assert_eq!(pos, message.len());
+
+        message.extend_from_slice(&[2, b'a', b'b', 0xc0, www as u8]);
+        let mut pos = www + 6;
+        assert_eq!(read_name(&message, &mut pos).unwrap(), "ab.www.example.com");
+        assert_eq!(pos, message.len());
+
+        // A pointer past the end
+        let mut pos = 0;
+        assert!(read_name(&[0xc0, 0xff], &mut pos).is_none());
+    }
+
+    #[test]
+    fn pointer_loops() {
+        // A pointer to itself
+        let mut pos = 0;
+        assert!(read_name(&[0xc0, 0], &mut pos).is_none());
+
+        // Pointers to each other, with a label in between
+        let mut pos = 0;
+        assert!(read_name(&[1, b'a', 0xc0, 4, 0xc0, 0], &mut pos).is_none());
+
+        // A chain of 64 pointers is followed, one more isn't
+        let chain = |len: usize| {
+            let mut message: Vec<u8> = (1..=len).flat_map(|i| vec![0xc0, 2 * i as u8]).collect();
+            message.push(0);
+            message
+        };
+        assert_eq!(read_name(&chain(64), &mut 0), Some(String::new()));
+        assert_eq!(read_name(&chain(65), &mut 0), None);
+
+        // An answer whose name, right after the question, points to itself
+        let message = answered(0x8180, &[record(&[0xc0, 29], 1, &[1, 2, 3, 4])]);
+        assert!(parse_response(&message).is_none());
+    }
+
+    #[test]
+    fn invalid_data() {
+        // The data length goes past the end of the message
+        let mut message = answered(0x8180, &[record(&EXAMPLE, 1, &[1, 2, 3, 4])]);
+        let len = message.len();
+        message[len - 6..len - 4].copy_from_slice(&[0, 5]);
+        assert!(parse_response(&message).is_none());
+
+        // A TXT chunk longer than the data
+        let message = answered(0x8180, &[record(&EXAMPLE, 16, b"\x05hi")]);
+        assert!(parse_response(&message).is_none());
+
+        // Addresses of the wrong length and an MX without a name are shown as hex instead of
+        // reading past their data
+        let message = answered(
+            0x8180,
+            &[
+                record(&EXAMPLE, 1, &[1, 2, 3]),
+                record(&EXAMPLE, 28, &[1, 2, 3, 4]),
+                record(&EXAMPLE, 15, &[0, 10]),
+                record(&EXAMPLE, 33, &[0, 1, 0, 5]),
+            ],
+        );
+        assert_eq!(
+            data(&message),
+            vec!["010203", "01020304", "000a", "00010005"]
+        );
+    }
+
+    #[test]
+    fn rcodes() {
+        let response = parse_response(&answered(0x8183, &[])).unwrap();
+        assert_eq!(response.rcode, 3);
+        assert_eq!(rcode_name(response.rcode), "NXDOMAIN");
+        assert!(!response.truncated);
+
+        let response = parse_response(&answered(0x8382, &[])).unwrap();
+        assert_eq!(rcode_name(response.rcode), "SERVFAIL");
+        assert!(response.truncated);
+
+        let names: Vec<_> = (0..7).map(rcode_name).collect();
+        assert_eq!(
+            names,
+            vec!["NOERROR", "FORMERR", "SERVFAIL", "NXDOMAIN", "NOTIMP", "REFUSED", "RCODE 6"]
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use crate::devices::{self, DeviceConf, Devices};
+use crate::log::Log;
+use crate::persist;
+use serde::{Deserialize, Serialize};
+use serde_json::{self, json};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+pub type GroupId = u32;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Group {
+    pub id: GroupId,
+    pub name: String,
+    #[serde(default)]
+    pub parent: Option<GroupId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum GroupStatus {
+    Up,
+    PartiallyDown,
+    Down,
+    Unknown,
+}
+
+impl Devices {
+    /// Returns `group` and all groups below it in the hierarchy
+    pub fn subgroups(&self, group: GroupId) -> Vec<GroupId> {
+        let groups = self.groups.lock();
+        let mut result = vec![group];
+        let mut i = 0;
+        while i < result.len() {
+            let parent = result[i];
+            for group in groups.iter() {
+                if group.parent == Some(parent) && !result.contains(&group.id) {
+                    result.push(group.id);
+                }
+            }
+            i += 1;
+        }
+        result
+    }
+
+    pub fn group_status(&self, group: GroupId) -> (GroupStatus, usize, usize) {
+        let groups = self.subgroups(group);
+        let (mut up, mut down) = (0, 0);
+
+        for device in self.all() {
+            let member = device
+                .conf
+                .lock()
+                .group
+                .map(|group| groups.contains(&group))
+                .unwrap_or(false);
+            if !member {
+                continue;
+            }
+            match device.icmpv4.lock().status.map(|s| s.0.is_up()) {
+                Some(true) => up += 1,
+                Some(false) => down += 1,
+                None => (),
+            }
+        }
+
+        let status = match (up, down) {
+            (0, 0) => GroupStatus::Unknown,
+            (_, 0) => GroupStatus::Up,
+            (0, _) => GroupStatus::Down,
+            _ => GroupStatus::PartiallyDown,
+        };
+
+        (status, up, down)
+    }
+
+    /// Allocates an id which was never used before, like `new_device_id`
+    pub fn new_group_id(&self) -> io::Result<GroupId> {
+        let mut ids = self.ids.lock();
+        let id = ids.next_group;
+        if id == GroupId::MAX {
+            return Err(io::Error::other("No group ids are left"));
+        }
+        ids.next_group = id + 1;
+        devices::save_ids(&self.data_dir, &ids)?;
+        Ok(id)
+    }
+
+    /// Whether a device can be placed in `group`
+    pub fn group_exists(&self, group: Option<GroupId>) -> bool {
+        match group {
+            Some(group) => self.groups.lock().iter().any(|g| g.id == group),
+            None => true,
+        }
+    }
+
+    fn valid_group(&self, group: &Group) -> bool {
+        match group.parent {
+            Some(parent) => {
+                // The parent must exist and not be the group or one of its descendants
+                self.groups.lock().iter().any(|g| g.id == parent)
+                    && !self.subgroups(group.id).contains(&parent)
+            }
+            None => true,
+        }
+    }
+
+    pub fn remove_group(self: &Arc<Self>, id: GroupId) {
+        let parent = {
+            let mut groups = self.groups.lock();
+            let parent = match groups.iter().find(|group| group.id == id) {
+                Some(group) => group.parent,
+                None => return,
+            };
+            groups.retain(|group| group.id != id);
+            for group in groups.iter_mut() {
+                if group.parent == Some(id) {
+                    group.parent = parent;
+                }
+            }
+            parent
+        };
+
+        // Move member devices up to the parent group
+        let members: Vec<DeviceConf> = self
+            .confs()
+            .into_iter()
+            .filter(|conf| conf.group == Some(id))
+            .collect();
+        for mut conf in members {
+            conf.group = parent;
+            self.change(conf.id, conf);
+        }
+    }
+
+    pub fn save_groups(&self) -> io::Result<()> {
+        let groups = serde_json::to_string_pretty(&*self.groups.lock()).unwrap();
+        persist::write(&self.data_dir.join("groups.json"), &groups)
+    }
+}
+
+pub fn load(data_dir: &Path, log: &Log) -> Result<Vec<Group>, String> {
+    persist::load_optional(&data_dir.join("groups.json"), log).map(Option::unwrap_or_default)
+}
+
+pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
+    let devices_ = devices.clone();
+    let list_groups = warp::path("groups")
+        .and(warp::get())
+        .and(warp::path::end())
+        .map(move || {
+            let groups = devices_.groups.lock().clone();
+            let groups: Vec<_> = groups
+                .into_iter()
+                .map(|group| {
+                    let (status, up, down) = devices_.group_status(group.id);
+                    json!({
+                        "id": group.id,
+                        "name": group.name,
+                        "parent": group.parent,
+                        "status": status,
+                        "up": up,
+                        "down": down,
+                    })
+                })
+                .collect();
+            serde_json::to_string(&groups).unwrap()
+        });
+
+    let devices_ = devices.clone();
+    let add = warp::path("group")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(move |mut group: Group| {
+            // A new group has no descendants, so only its parent needs to exist. The id is
+            // allocated after, so rejected groups don't use one up.
+            if !devices_.group_exists(group.parent) {
+                return "error";
+            }
+            group.id = match devices_.new_group_id() {
+                Ok(id) => id,
+                Err(_) => return "error",
+            };
+            devices_.groups.lock().push(group);
+            if devices_.save_groups().is_err() {
+                return "error";
+            }
+
+            ""
+        });
+
+    let devices_ = devices.clone();
+    let edit = warp::path!("group" / u32)
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(move |id, mut group: Group| {
+            group.id = id;
+            if !devices_.valid_group(&group) {
+                return "error";
+            }
+            match devices_.groups.lock().iter_mut().find(|g| g.id == id) {
+                Some(existing) => *existing = group,
+                None => return "error",
+            }
+            if devices_.save_groups().is_err() {
+                return "error";
+            }
+
+            ""
+        });
+
+    let devices_ = devices.clone();
+    let remove = warp::path!("group" / u32)
+        .and(warp::delete())
+        .map(move |id| {
+            devices_.remove_group(id);
+            if devices_.save_groups().is_err() || devices_.save().is_err() {
+                return "error";
+            }
+
+            ""
+        });
+
+    list_groups.or(add).or(edit).or(remove).boxed()
+}
@@ -0,0 +1,190 @@
+use crate::devices::{DeviceId, Devices, Quality, Round, ServiceStatus};
+use crate::maintenance::unix_time;
+use crate::storage::Storage;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::{self, json};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::SystemTime;
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+const MAX_ENTRIES: usize = 10000;
+
+// Rounds kept per device
+const MAX_ROUNDS: usize = 1440;
+
+/// The longest range availability is computed for, in seconds
+const MAX_RANGE: u64 = 366 * 86400;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Transition {
+    pub device: DeviceId,
+    pub status: ServiceStatus,
+    pub time: SystemTime,
+    /// The device was under maintenance
+    pub maintenance: bool,
+    pub quality: Option<Quality>,
+}
+
+pub struct History {
+    entries: Mutex<VecDeque<Transition>>,
+    rounds: Mutex<HashMap<DeviceId, VecDeque<Round>>>,
+    storage: Arc<dyn Storage>,
+}
+
+impl History {
+    /// Starts with the history kept by `storage`
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        let entries = storage.load_transitions(MAX_ENTRIES).into();
+        let mut rounds: HashMap<DeviceId, VecDeque<Round>> = HashMap::new();
+        for (device, round) in storage.load_rounds(MAX_ROUNDS) {
+            rounds.entry(device).or_default().push_back(round);
+        }
+
+        History {
+            entries: Mutex::new(entries),
+            rounds: Mutex::new(rounds),
+            storage,
+        }
+    }
+
+    pub fn record_round(&self, device: DeviceId, round: Round) {
+        self.storage.record_round(device, &round);
+        let mut rounds = self.rounds.lock();
+        let rounds = rounds.entry(device).or_default();
+        rounds.push_back(round);
+        if rounds.len() > MAX_ROUNDS {
+            rounds.pop_front();
+        }
+    }
+
+    pub fn rounds(&self, device: DeviceId) -> Vec<Round> {
+        self.rounds
+            .lock()
+            .get(&device)
+            .map(|rounds| rounds.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn remove(&self, device: DeviceId) {
+        self.storage.remove_history(device);
+        self.entries
+            .lock()
+            .retain(|transition| transition.device != device);
+        self.rounds.lock().remove(&device);
+    }
+
+    pub fn record(&self, transition: Transition) {
+        self.storage.record_transition(&transition);
+        let mut entries = self.entries.lock();
+        entries.push_back(transition);
+        if entries.len() > MAX_ENTRIES {
+            entries.pop_front();
+        }
+    }
+
+    pub fn device(&self, device: DeviceId) -> Vec<Transition> {
+        self.entries
+            .lock()
+            .iter()
+            .filter(|transition| transition.device == device)
+            .cloned()
+            .collect()
+    }
+}
+
+fn overlap(start: u64, end: u64, periods: &[(u64, u64)]) -> u64 {
+    periods
+        .iter()
+        .map(|&(from, to)| end.min(to).saturating_sub(start.max(from)))
+        .sum()
+}
+
+/// Returns the fraction of the known time within `from..to` the device was up,
+/// ignoring time in the `excluded` periods, which must not overlap
+pub fn availability(
+    transitions: &[Transition],
+    from: u64,
+    to: u64,
+    excluded: &[(u64, u64)],
+) -> Option<f64> {
+    let (mut up, mut known) = (0, 0);
+
+    for (i, transition) in transitions.iter().enumerate() {
+        let start = unix_time(transition.time).max(from);
+        let end = transitions
+            .get(i + 1)
+            .map(|next| unix_time(next.time))
+            .unwrap_or(to)
+            .min(to);
+
+        if start >= end {
+            continue;
+        }
+
+        let time = (end - start) - overlap(start, end, excluded);
+        known += time;
+        if transition.status.is_up() {
+            up += time;
+        }
+    }
+
+    if known > 0 {
+        Some(up as f64 / known as f64)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AvailabilityQuery {
+    from: Option<u64>,
+    to: Option<u64>,
+    #[serde(default)]
+    exclude_maintenance: bool,
+}
+
+pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
+    let devices_ = devices.clone();
+    let history = warp::path!("device" / u32 / "history")
+        .and(warp::get())
+        .map(move |id| serde_json::to_string(&devices_.history.device(id)).unwrap());
+
+    let devices_ = devices.clone();
+    let rounds = warp::path!("device" / u32 / "rounds")
+        .and(warp::get())
+        .map(move |id| serde_json::to_string(&devices_.history.rounds(id)).unwrap());
+
+    let devices_ = devices.clone();
+    let availability = warp::path!("device" / u32 / "availability")
+        .and(warp::get())
+        .and(warp::query())
+        .map(move |id, query: AvailabilityQuery| {
+            let now = unix_time(SystemTime::now());
+            let to = query.to.unwrap_or(now).min(now);
+            // Default to the last 30 days
+            let from = query
+                .from
+                .unwrap_or_else(|| to.saturating_sub(30 * 86400))
+                .max(to.saturating_sub(MAX_RANGE));
+
+            let excluded = if query.exclude_maintenance {
+                devices_.maintenance_periods(id, from, to)
+            } else {
+                Vec::new()
+            };
+
+            let availability =
+                self::availability(&devices_.history.device(id), from, to, &excluded);
+
+            serde_json::to_string(&json!({
+                "from": from,
+                "to": to,
+                "availability": availability,
+            }))
+            .unwrap()
+        });
+
+    history.or(rounds).or(availability).boxed()
+}
@@ -0,0 +1,187 @@
+use crate::devices::{Device, DeviceChange, Devices, ServiceStatus};
+use crate::history::Transition;
+use crate::monitor::CancelToken;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::lookup_host;
+use tokio::time::{delay_for, Duration};
+
+async fn resolve(hostname: &str) -> Result<Vec<IpAddr>, String> {
+    let addresses: Vec<IpAddr> = lookup_host((hostname, 0))
+        .await
+        .map_err(|error| error.to_string())?
+        .map(|address| address.ip())
+        .collect();
+
+    if addresses.is_empty() {
+        return Err("No addresses found".to_owned());
+    }
+
+    Ok(addresses)
+}
+
+/// Points the device at the first IPv4 address, restarting its monitor if the address changed
+fn resolved(
+    devices: &Arc<Devices>,
+    device: &Arc<Device>,
+    addresses: Vec<IpAddr>,
+    cancel: &CancelToken,
+) {
+    let ipv4 = addresses.iter().find_map(|address| match address {
+        IpAddr::V4(ip) => Some(*ip),
+        IpAddr::V6(_) => None,
+    });
+
+    let ipv4 = match ipv4 {
+        Some(ipv4) => ipv4,
+        None => return unsupported(devices, device, addresses),
+    };
+
+    let monitoring = {
+        let mut icmpv4 = device.icmpv4.lock();
+        icmpv4.addresses = addresses;
+        icmpv4.dns_error = None;
+        icmpv4.monitor.is_some()
+    };
+
+    let id = device.conf.lock().id;
+    let mut changed = false;
+    devices.update(id, |conf| {
+        // Changing the hostname cancels the resolver while the configuration is locked
+        if cancel.cancelled() || conf.ipv4 == Some(ipv4) {
+            return;
+        }
+        if let Some(old) = conf.ipv4 {
+            devices.log.note(&format!(
+                "Device {} changed address from {} to {}",
+                conf.desc(),
+                old,
+                ipv4
+            ));
+        }
+        conf.ipv4 = Some(ipv4);
+        changed = true;
+    });
+
+    if changed {
+        devices.save_logged();
+    } else if !monitoring && !cancel.cancelled() {
+        // Monitoring was stopped by an earlier resolution failure
+        devices.start_monitor(device, &mut device.icmpv4.lock(), ipv4);
+    }
+}
+
+/// Stops monitoring a device whose hostname only has IPv6 addresses, which aren't monitored.
+/// Its status becomes unknown instead of a failure.
+fn unsupported(devices: &Arc<Devices>, device: &Arc<Device>, addresses: Vec<IpAddr>) {
+    let (id, desc) = {
+        let conf = device.conf.lock();
+        (conf.id, conf.desc())
+    };
+
+    let change = {
+        let mut icmpv4 = device.icmpv4.lock();
+        if icmpv4.addresses != addresses {
+            devices.log.note(&format!(
+                "Device {} only has IPv6 addresses, which aren't monitored",
+                desc
+            ));
+        }
+        icmpv4.addresses = addresses;
+        icmpv4.dns_error = None;
+
+        if let Some(token) = icmpv4.monitor.take() {
+            token.cancel();
+        }
+        icmpv4.quality = None;
+        icmpv4.reason = None;
+
+        let old = icmpv4.status.take();
+        old.map(|_| DeviceChange::IPv4Status {
+            device: id,
+            old,
+            new: None,
+            quality: None,
+            reason: None,
+        })
+    };
+
+    if let Some(change) = change {
+        devices.changes.send(change).ok();
+    }
+}
+
+/// Stops monitoring the device and marks it as failing DNS resolution
+fn failed(devices: &Arc<Devices>, device: &Arc<Device>, error: String) {
+    let id = device.conf.lock().id;
+    let time = SystemTime::now();
+
+    let change = {
+        let mut icmpv4 = device.icmpv4.lock();
+        icmpv4.dns_error = Some(error);
+
+        if let Some(token) = icmpv4.monitor.take() {
+            token.cancel();
+        }
+
+        let old = icmpv4.status;
+        if old.map(|s| s.0) == Some(ServiceStatus::DnsFailure) {
+            return;
+        }
+
+        let new = Some((ServiceStatus::DnsFailure, time));
+        icmpv4.status = new;
+        icmpv4.quality = None;
+        icmpv4.reason = None;
+
+        devices.history.record(Transition {
+            device: id,
+            status: ServiceStatus::DnsFailure,
+            time,
+            maintenance: icmpv4.maintenance,
+            quality: None,
+        });
+
+        DeviceChange::IPv4Status {
+            device: id,
+            old,
+            new,
+            quality: None,
+            reason: None,
+        }
+    };
+
+    devices.changes.send(change.clone()).ok();
+
+    let devices = devices.clone();
+    tokio::spawn(async move { devices.notify(change).await });
+}
+
+/// Resolves the hostname of a device periodically until cancelled
+pub async fn resolver(
+    devices: Arc<Devices>,
+    device: Arc<Device>,
+    hostname: String,
+    cancel: CancelToken,
+) {
+    loop {
+        let result = resolve(&hostname).await;
+
+        if cancel.cancelled() {
+            break;
+        }
+
+        match result {
+            Ok(addresses) => resolved(&devices, &device, addresses, &cancel),
+            Err(error) => failed(&devices, &device, error),
+        }
+
+        let interval = devices.conf.lock().resolve_interval;
+        delay_for(Duration::from_secs(interval.max(1))).await;
+
+        if cancel.cancelled() {
+            break;
+        }
+    }
+}
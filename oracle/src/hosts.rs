@@ -0,0 +1,172 @@
+//! An inventory of the hosts seen on the network, alerting on unknown MAC addresses
+//! and known MAC addresses with a new IP address
+
+use crate::devices::{DeviceChange, Devices};
+use crate::log::{Kind, Log};
+use crate::mac::MacAddr;
+use crate::passive::Neighbour;
+use crate::persist;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KnownHost {
+    pub mac: MacAddr,
+    pub ipv4: Ipv4Addr,
+    pub hostname: Option<String>,
+    pub first_seen: SystemTime,
+    /// Brought up to date at most every `LAST_SEEN_RESOLUTION`
+    pub last_seen: SystemTime,
+}
+
+/// Loads the inventory, `None` if there isn't one yet
+pub fn load(data_dir: &Path, log: &Log) -> Result<Option<Vec<KnownHost>>, String> {
+    persist::load_optional(&data_dir.join("hosts.json"), log)
+}
+
+fn save(data_dir: &Path, hosts: &[KnownHost]) -> io::Result<()> {
+    persist::write(
+        &data_dir.join("hosts.json"),
+        &serde_json::to_string_pretty(hosts).unwrap(),
+    )
+}
+
+/// How often `last_seen` is brought up to date, so the inventory isn't saved every scan
+const LAST_SEEN_RESOLUTION: Duration = Duration::from_secs(15 * 60);
+
+/// Collapses neighbours to one per MAC address, preferring the address already known
+/// so a host answering on several addresses isn't reported as moving between them
+fn collapse<'a>(hosts: &[KnownHost], neighbours: &'a [Neighbour]) -> Vec<&'a Neighbour> {
+    let mut collapsed: Vec<&Neighbour> = Vec::new();
+    for neighbour in neighbours {
+        match collapsed.iter_mut().find(|n| n.mac == neighbour.mac) {
+            Some(existing) => {
+                let known = hosts
+                    .iter()
+                    .any(|host| host.mac == neighbour.mac && host.ipv4 == neighbour.ipv4);
+                if known {
+                    *existing = neighbour;
+                }
+            }
+            None => collapsed.push(neighbour),
+        }
+    }
+    collapsed
+}
+
+/// Records neighbours in the inventory and returns the changes to alert on.
+/// Without an inventory, the first neighbours seen are recorded without alerts.
+fn record(devices: &Devices, neighbours: &[Neighbour]) -> Vec<DeviceChange> {
+    let mut inventory = devices.discovery.hosts.lock();
+    let learning = inventory.is_none();
+    if learning && neighbours.is_empty() {
+        return Vec::new();
+    }
+    let hosts = inventory.get_or_insert_with(Vec::new);
+    let time = SystemTime::now();
+    let mut changes = Vec::new();
+    let mut changed = learning;
+
+    for neighbour in collapse(hosts, neighbours) {
+        match hosts.iter_mut().find(|host| host.mac == neighbour.mac) {
+            Some(host) => {
+                if host.ipv4 != neighbour.ipv4 {
+                    changes.push(DeviceChange::HostMoved {
+                        mac: host.mac,
+                        old: host.ipv4,
+                        new: neighbour.ipv4,
+                        time,
+                    });
+                    host.ipv4 = neighbour.ipv4;
+                    changed = true;
+                }
+                if neighbour.hostname.is_some() && host.hostname != neighbour.hostname {
+                    host.hostname = neighbour.hostname.clone();
+                    changed = true;
+                }
+                let stale = time
+                    .duration_since(host.last_seen)
+                    .is_ok_and(|age| age >= LAST_SEEN_RESOLUTION);
+                if stale {
+                    host.last_seen = time;
+                    changed = true;
+                }
+            }
+            None => {
+                changes.push(DeviceChange::NewHost {
+                    mac: neighbour.mac,
+                    ipv4: neighbour.ipv4,
+                    hostname: neighbour.hostname.clone(),
+                    time,
+                });
+                hosts.push(KnownHost {
+                    mac: neighbour.mac,
+                    ipv4: neighbour.ipv4,
+                    hostname: neighbour.hostname.clone(),
+                    first_seen: time,
+                    last_seen: time,
+                });
+                changed = true;
+            }
+        }
+    }
+
+    if !changed {
+        return changes;
+    }
+
+    // Hosts are only recorded from the passive scan, so saves can't race
+    let hosts = hosts.clone();
+    drop(inventory);
+    if let Err(error) = save(&devices.data_dir, &hosts) {
+        devices.log.log(
+            Kind::Error,
+            &format!("Unable to save the host inventory: {}", error),
+        );
+        if learning {
+            // Stay in learning mode until the first inventory is saved
+            *devices.discovery.hosts.lock() = None;
+        }
+    }
+
+    if learning {
+        Vec::new()
+    } else {
+        changes
+    }
+}
+
+/// Records neighbours in the inventory and notifies about new and moved hosts
+pub async fn observe(devices: &Arc<Devices>, neighbours: &[Neighbour]) {
+    for change in record(devices, neighbours) {
+        devices.changes.send(change.clone()).ok();
+        devices.notify(change).await;
+    }
+}
+
+pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("discovery" / "hosts")
+        .and(warp::get())
+        .map(move || {
+            let hosts = devices.discovery.hosts.lock().clone().unwrap_or_default();
+            let hosts: Vec<_> = hosts
+                .into_iter()
+                .map(|host| {
+                    let device = devices
+                        .find(|conf| conf.mac == Some(host.mac))
+                        .map(|conf| conf.id);
+                    let mut host = serde_json::to_value(host).unwrap();
+                    host["device"] = json!(device);
+                    host
+                })
+                .collect();
+            serde_json::to_string(&hosts).unwrap()
+        })
+        .boxed()
+}
@@ -1,7 +1,6 @@
 use futures::{SinkExt, StreamExt};
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::str;
 use std::sync::Arc;
 use std::time::SystemTime;
@@ -53,6 +52,10 @@ impl Log {
     pub fn note(&self, val: &str) {
         self.log(Kind::Note, val);
     }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Entry> {
+        self.sender.subscribe()
+    }
 }
 
 pub fn websocket(log: Arc<Log>) -> BoxedFilter<(impl Reply,)> {
@@ -0,0 +1,87 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    /// Accepts `aa:bb:cc:dd:ee:ff` and `aa-bb-cc-dd-ee-ff`, but not both separators at once
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid MAC address {}", s);
+        let mut mac = [0; 6];
+        let s = s.trim();
+        let separator = if s.contains(':') { ':' } else { '-' };
+        let mut parts = s.split(separator);
+
+        for byte in mac.iter_mut() {
+            let part = parts.next().ok_or_else(invalid)?;
+            if part.len() != 2 || !part.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(MacAddr(mac))
+    }
+}
+
+impl Serialize for MacAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let mac = MacAddr([0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0xef]);
+        assert_eq!("00:1a:2b:3c:4d:ef".parse(), Ok(mac));
+        assert_eq!("00-1A-2B-3C-4D-EF".parse(), Ok(mac));
+        assert_eq!(" 00:1a:2b:3c:4d:ef\n".parse(), Ok(mac));
+        assert_eq!(mac.to_string(), "00:1a:2b:3c:4d:ef");
+
+        for invalid in &[
+            "",
+            "00:1a:2b:3c:4d",
+            "00:1a:2b:3c:4d:ef:01",
+            "00:1a:2b:3c:4d:",
+            "00:1a:2b:3c:4d:eg",
+            "00:1a:2b:3c:4d:+f",
+            "0:1a:2b:3c:4d:ef0",
+            "001a2b3c4def",
+            // Mixed separators
+            "00:1a-2b:3c:4d:ef",
+            "00-1a-2b-3c-4d:ef",
+        ] {
+            assert!(invalid.parse::<MacAddr>().is_err(), "{}", invalid);
+        }
+    }
+}
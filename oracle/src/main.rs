@@ -1,18 +1,50 @@
 use log::Kind;
-use std::{panic, sync::Arc};
+use std::net::SocketAddr;
+use std::{panic, process, sync::Arc};
+use structopt::StructOpt;
 use tokio::spawn;
-use tracing_subscriber;
+use tracing_subscriber::EnvFilter;
 
+mod admin;
+mod cli;
+mod command;
 mod devices;
+mod discovery;
+mod dns;
+mod groups;
+mod history;
+mod hostname;
+mod hosts;
 mod log;
+mod mac;
+mod maintenance;
+mod mdns;
 mod monitor;
 mod notifier;
+mod passive;
+mod persist;
 mod ping;
+#[cfg(test)]
+mod simulator;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 mod state;
+mod storage;
+#[cfg(test)]
+mod tests;
+mod traceroute;
+mod transfer;
 mod webserver;
+mod wol;
 
 fn main() {
-    tracing_subscriber::fmt::init();
+    let mut options = cli::Options::from_args();
+
+    let filter = match &options.log_level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::from_default_env(),
+    };
+    tracing_subscriber::fmt().with_env_filter(filter).init();
 
     let log = Arc::new(log::Log::new());
 
@@ -43,12 +75,33 @@ fn main() {
         .build()
         .unwrap()
         .block_on(async move {
-            let state = state::load();
-            let devices = devices::load(state.clone(), log.clone());
+            if let Some(command) = options.command.take() {
+                if let Err(error) = admin::run(&options, command, log).await {
+                    eprintln!("error: {}", error);
+                    process::exit(1);
+                }
+                return;
+            }
+
+            // Subscribe first to record what's logged while opening the storage
+            let entries = log.subscribe();
+            let storage = storage::open(&options.data_dir, options.config_file(), log.clone());
+            spawn(storage::log_recorder(entries, storage.clone()));
+            let state = state::load(&*storage);
+            let devices = devices::load(
+                state.clone(),
+                log.clone(),
+                storage,
+                options.data_dir.clone(),
+            );
+
+            let port = options.port.unwrap_or_else(|| state.lock().config.web_port);
             let web_server = spawn(webserver::webserver(
                 devices.clone(),
                 state.clone(),
                 log.clone(),
+                options.web_dir.clone(),
+                SocketAddr::new(options.bind, port),
             ));
             log.note("Server started up");
             web_server.await.unwrap();
@@ -0,0 +1,244 @@
+use crate::devices::{self, DeviceId, Devices};
+use crate::groups::GroupId;
+use crate::log::Log;
+use crate::persist;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+pub type MaintenanceId = u32;
+
+/// The shortest time between recurrences of a window, keeping `occurrences` short
+const MIN_REPEAT: u64 = 3600;
+
+/// A maintenance window. Times are in seconds since the Unix epoch.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Maintenance {
+    pub id: MaintenanceId,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub devices: Vec<DeviceId>,
+    /// Groups, including their subgroups, which are under maintenance
+    #[serde(default)]
+    pub groups: Vec<GroupId>,
+    pub start: u64,
+    /// Length of the window in seconds
+    pub duration: u64,
+    /// Seconds between recurrences, the window only happens once if `None`
+    #[serde(default)]
+    pub repeat: Option<u64>,
+}
+
+impl Maintenance {
+    /// Returns the parts of the window which overlap the `from..to` range
+    pub fn occurrences(&self, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let clip = |start: u64| {
+            let end = start.saturating_add(self.duration);
+            if end > from && start < to {
+                Some((start.max(from), end.min(to)))
+            } else {
+                None
+            }
+        };
+
+        match self.repeat {
+            Some(repeat) if repeat > 0 => {
+                // Start with the last occurrence beginning before `from`
+                let mut start = if from > self.start {
+                    self.start + (from - self.start) / repeat * repeat
+                } else {
+                    self.start
+                };
+                let mut result = Vec::new();
+                while start < to {
+                    result.extend(clip(start));
+                    start = match start.checked_add(repeat) {
+                        Some(next) => next,
+                        None => break,
+                    };
+                }
+                result
+            }
+            _ => clip(self.start).into_iter().collect(),
+        }
+    }
+
+    /// Whether the window can be added. Recurrences can't overlap or be more frequent than
+    /// `MIN_REPEAT`.
+    fn valid(&self) -> bool {
+        self.duration > 0
+            && self
+                .repeat
+                .is_none_or(|repeat| repeat >= self.duration.max(MIN_REPEAT))
+    }
+
+    pub fn active(&self, time: u64) -> bool {
+        !self.occurrences(time, time + 1).is_empty()
+    }
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl Devices {
+    fn maintenance_for(&self, device: DeviceId) -> Vec<Maintenance> {
+        let group = self
+            .device(device)
+            .and_then(|device| device.conf.lock().group);
+        let windows = self.maintenance.lock().clone();
+        windows
+            .into_iter()
+            .filter(|window| {
+                window.devices.contains(&device)
+                    || window.groups.iter().any(|&g| {
+                        group
+                            .map(|group| self.subgroups(g).contains(&group))
+                            .unwrap_or(false)
+                    })
+            })
+            .collect()
+    }
+
+    pub fn in_maintenance(&self, device: DeviceId, time: SystemTime) -> bool {
+        let time = unix_time(time);
+        self.maintenance_for(device)
+            .iter()
+            .any(|window| window.active(time))
+    }
+
+    /// Returns the sorted and merged periods of maintenance for a device within `from..to`
+    pub fn maintenance_periods(&self, device: DeviceId, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let mut periods: Vec<_> = self
+            .maintenance_for(device)
+            .iter()
+            .flat_map(|window| window.occurrences(from, to))
+            .collect();
+        periods.sort();
+
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+        for (start, end) in periods {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+
+    /// Allocates an id which was never used before, like `new_device_id`
+    pub fn new_maintenance_id(&self) -> io::Result<MaintenanceId> {
+        let mut ids = self.ids.lock();
+        let id = ids.next_maintenance;
+        if id == MaintenanceId::MAX {
+            return Err(io::Error::other("No maintenance window ids are left"));
+        }
+        ids.next_maintenance = id + 1;
+        devices::save_ids(&self.data_dir, &ids)?;
+        Ok(id)
+    }
+
+    pub fn save_maintenance(&self) -> io::Result<()> {
+        let windows = serde_json::to_string_pretty(&*self.maintenance.lock()).unwrap();
+        persist::write(&self.data_dir.join("maintenance.json"), &windows)
+    }
+}
+
+pub fn load(data_dir: &Path, log: &Log) -> Result<Vec<Maintenance>, String> {
+    persist::load_optional(&data_dir.join("maintenance.json"), log).map(Option::unwrap_or_default)
+}
+
+pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
+    let devices_ = devices.clone();
+    let list = warp::path("maintenance")
+        .and(warp::get())
+        .and(warp::path::end())
+        .map(move || serde_json::to_string(&*devices_.maintenance.lock()).unwrap());
+
+    let devices_ = devices.clone();
+    let add = warp::path("maintenance")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(move |mut window: Maintenance| {
+            if !window.valid() {
+                return "error";
+            }
+            window.id = match devices_.new_maintenance_id() {
+                Ok(id) => id,
+                Err(_) => return "error",
+            };
+            devices_.maintenance.lock().push(window);
+            if devices_.save_maintenance().is_err() {
+                return "error";
+            }
+
+            ""
+        });
+
+    let devices_ = devices.clone();
+    let remove = warp::path!("maintenance" / u32)
+        .and(warp::delete())
+        .map(move |id| {
+            devices_.maintenance.lock().retain(|window| window.id != id);
+            if devices_.save_maintenance().is_err() {
+                return "error";
+            }
+
+            ""
+        });
+
+    list.or(add).or(remove).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(start: u64, duration: u64, repeat: Option<u64>) -> Maintenance {
+        Maintenance {
+            id: 0,
+            description: String::new(),
+            devices: Vec::new(),
+            groups: Vec::new(),
+            start,
+            duration,
+            repeat,
+        }
+    }
+
+    #[test]
+    fn occurrences() {
+        let daily = window(3600, 7200, Some(86400));
+        assert_eq!(daily.occurrences(0, 3600), vec![]);
+        assert_eq!(
+            daily.occurrences(7200, 2 * 86400),
+            vec![(7200, 10800), (86400 + 3600, 86400 + 10800)]
+        );
+        assert!(daily.active(86400 + 3600));
+        assert!(!daily.active(86400 + 10800));
+
+        // Stops at the end of time instead of overflowing
+        let late = window(u64::MAX - 7200, 3600, Some(4000));
+        assert_eq!(
+            late.occurrences(u64::MAX - 3600, u64::MAX),
+            vec![(u64::MAX - 3200, u64::MAX)]
+        );
+    }
+
+    #[test]
+    fn validity() {
+        assert!(window(0, 3600, None).valid());
+        assert!(window(0, 3600, Some(86400)).valid());
+        assert!(window(0, 60, Some(MIN_REPEAT)).valid());
+        assert!(!window(0, 0, None).valid());
+        assert!(!window(0, 60, Some(1)).valid());
+        assert!(!window(0, 7200, Some(3600)).valid());
+    }
+}
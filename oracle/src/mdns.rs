@@ -0,0 +1,259 @@
+//! Browses multicast DNS for hosts and the services they advertise
+
+use crate::devices::Devices;
+use crate::discovery::Candidate;
+use crate::dns::{self, RecordType, Response};
+use crate::log::Kind;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::udp::SendHalf;
+use tokio::net::UdpSocket;
+use tokio::time::{delay_until, Duration, Instant};
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+
+/// Answered with the service types on the network
+const SERVICES: &str = "_services._dns-sd._udp.local";
+
+/// The most service types browsed, as any host can announce types
+const MAX_TYPES: usize = 64;
+
+/// A host advertised over mDNS
+#[derive(Debug, Serialize, Clone)]
+pub struct MdnsHost {
+    pub hostname: String,
+    pub ipv4: Ipv4Addr,
+    /// Service types such as `_http._tcp.local`
+    pub services: Vec<String>,
+    pub seen: SystemTime,
+}
+
+fn open() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    // Other mDNS responders on the host use the same port
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into_udp_socket())
+}
+
+async fn query(tx: &mut SendHalf, name: &str) {
+    if let Ok(mut query) = dns::encode_query(0, name, RecordType::Ptr) {
+        // mDNS queries have no flags set
+        query[2] = 0;
+        query[3] = 0;
+        tx.send_to(&query, &SocketAddr::from((GROUP, PORT)))
+            .await
+            .ok();
+    }
+}
+
+/// Finds the service types and hosts in a response
+fn parse(response: &Response) -> (Vec<String>, Vec<MdnsHost>) {
+    let records: Vec<_> = response
+        .answers
+        .iter()
+        .chain(response.additional.iter())
+        .collect();
+
+    let mut types = Vec::new();
+    let mut instances = Vec::new();
+    let mut targets = HashMap::new();
+    for record in &records {
+        match record.record_type {
+            12 if record.name.eq_ignore_ascii_case(SERVICES) => types.push(record.data.clone()),
+            12 => instances.push((record.name.clone(), record.data.clone())),
+            33 => {
+                targets.insert(record.name.to_lowercase(), record.data.to_lowercase());
+            }
+            _ => (),
+        }
+    }
+
+    let hosts = records
+        .iter()
+        .filter(|record| record.record_type == 1)
+        .filter_map(|record| {
+            let hostname = record.name.to_lowercase();
+            let services = instances
+                .iter()
+                .filter(|(_, instance)| targets.get(&instance.to_lowercase()) == Some(&hostname))
+                .map(|(service, _)| service.clone())
+                .collect();
+            Some(MdnsHost {
+                hostname: record.name.clone(),
+                ipv4: record.data.parse().ok()?,
+                services,
+                seen: SystemTime::now(),
+            })
+        })
+        .collect();
+
+    (types, hosts)
+}
+
+/// Names the device using the host's address or adds the host as a candidate.
+/// Returns whether a device changed.
+fn observed(devices: &Arc<Devices>, host: MdnsHost) -> bool {
+    {
+        let mut mdns = devices.discovery.mdns.lock();
+        mdns.retain(|h| h.ipv4 != host.ipv4 && h.hostname != host.hostname);
+        mdns.push(host.clone());
+    }
+
+    let name = host.hostname.trim_end_matches(".local").to_owned();
+
+    let device = devices.find(|conf| conf.ipv4 == Some(host.ipv4));
+
+    if let Some(conf) = device {
+        let mut changed = false;
+        devices.update(conf.id, |conf| {
+            if conf.ipv4 != Some(host.ipv4) || conf.name.is_some() {
+                return;
+            }
+
+            devices.log.note(&format!(
+                "Device {} is named {} over mDNS",
+                conf.desc(),
+                name
+            ));
+            conf.name = Some(name);
+            changed = true;
+        });
+        return changed;
+    }
+
+    let new = devices.discovery.found(Candidate {
+        ipv4: host.ipv4,
+        mac: None,
+        hostname: Some(name.clone()),
+        services: host.services,
+        rtt: None,
+        seen: host.seen,
+    });
+    if new {
+        devices.log.note(&format!(
+            "New host {} ({}) found over mDNS",
+            name, host.ipv4
+        ));
+    }
+
+    false
+}
+
+/// Listens for mDNS announcements and periodically browses for services
+pub async fn listener(devices: Arc<Devices>) {
+    let interval = devices.conf.lock().mdns_interval;
+    if interval == 0 {
+        return;
+    }
+
+    let socket = match open() {
+        Ok(socket) => socket,
+        Err(error) => {
+            devices.log.log(
+                Kind::Error,
+                &format!("Unable to listen for mDNS: {}", error),
+            );
+            return;
+        }
+    };
+    let (mut rx, mut tx) = socket.split();
+
+    let mut types: Vec<String> = Vec::new();
+    let mut next = Instant::now();
+    let mut buffer = [0; 9000];
+
+    loop {
+        tokio::select! {
+            result = rx.recv_from(&mut buffer) => {
+                let size = match result {
+                    Ok((size, _)) => size,
+                    Err(_) => continue,
+                };
+                let response = match dns::parse_response(&buffer[0..size]) {
+                    Some(response) => response,
+                    None => continue,
+                };
+
+                let (new_types, hosts) = parse(&response);
+                for service in new_types {
+                    if types.len() < MAX_TYPES && !types.contains(&service) {
+                        query(&mut tx, &service).await;
+                        types.push(service);
+                    }
+                }
+
+                let mut changed = false;
+                for host in hosts {
+                    changed |= observed(&devices, host);
+                }
+                if changed {
+                    devices.save_logged();
+                }
+            }
+            _ = delay_until(next) => {
+                query(&mut tx, SERVICES).await;
+                for service in &types {
+                    query(&mut tx, service).await;
+                }
+                next = Instant::now() + Duration::from_secs(interval);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_types() {
+        // The service types on the network, answering a query for `SERVICES`
+        let message = [
+            0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x09, 0x5f,
+            0x73, 0x65, 0x72, 0x76, 0x69, 0x63, 0x65, 0x73, 0x07, 0x5f, 0x64, 0x6e, 0x73, 0x2d,
+            0x73, 0x64, 0x04, 0x5f, 0x75, 0x64, 0x70, 0x05, 0x6c, 0x6f, 0x63, 0x61, 0x6c, 0x00,
+            0x00, 0x0c, 0x00, 0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x0c, 0x04, 0x5f, 0x69, 0x70,
+            0x70, 0x04, 0x5f, 0x74, 0x63, 0x70, 0xc0, 0x23, 0xc0, 0x0c, 0x00, 0x0c, 0x00, 0x01,
+            0x00, 0x00, 0x11, 0x94, 0x00, 0x0d, 0x05, 0x5f, 0x68, 0x74, 0x74, 0x70, 0x04, 0x5f,
+            0x74, 0x63, 0x70, 0xc0, 0x23,
+        ];
+
+        let (types, hosts) = parse(&dns::parse_response(&message).unwrap());
+        assert_eq!(types, vec!["_ipp._tcp.local", "_http._tcp.local"]);
+        assert!(hosts.is_empty());
+    }
+
+    #[test]
+    fn service_instance() {
+        // A printer answering a query for `_ipp._tcp.local`. The PTR record names the
+        // instance, its SRV record the host and the host's A record the address. The
+        // additional records have the cache-flush bit set in their class.
+        let message = [
+            0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x04, 0x5f,
+            0x69, 0x70, 0x70, 0x04, 0x5f, 0x74, 0x63, 0x70, 0x05, 0x6c, 0x6f, 0x63, 0x61, 0x6c,
+            0x00, 0x00, 0x0c, 0x00, 0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x11, 0x0e, 0x4f, 0x66,
+            0x66, 0x69, 0x63, 0x65, 0x20, 0x50, 0x72, 0x69, 0x6e, 0x74, 0x65, 0x72, 0xc0, 0x0c,
+            0xc0, 0x27, 0x00, 0x21, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x10, 0x00, 0x00,
+            0x00, 0x00, 0x02, 0x77, 0x07, 0x70, 0x72, 0x69, 0x6e, 0x74, 0x65, 0x72, 0xc0, 0x16,
+            0xc0, 0x27, 0x00, 0x10, 0x80, 0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x0a, 0x09, 0x74,
+            0x78, 0x74, 0x76, 0x65, 0x72, 0x73, 0x3d, 0x31, 0xc0, 0x4a, 0x00, 0x01, 0x80, 0x01,
+            0x00, 0x00, 0x00, 0x78, 0x00, 0x04, 0xc0, 0xa8, 0x01, 0x14,
+        ];
+
+        let (types, hosts) = parse(&dns::parse_response(&message).unwrap());
+        assert!(types.is_empty());
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].hostname, "printer.local");
+        assert_eq!(hosts[0].ipv4, Ipv4Addr::new(192, 168, 1, 20));
+        assert_eq!(hosts[0].services, vec!["_ipp._tcp.local"]);
+    }
+}
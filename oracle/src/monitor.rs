@@ -1,9 +1,16 @@
-use crate::devices::{Device, DeviceChange, Devices, ServiceStatus};
+use crate::devices::{Device, DeviceChange, DeviceId, Devices, Quality, Round, ServiceStatus};
+use crate::history::Transition;
+use crate::ping::{Ping, PingError};
+use crate::state::Burst;
+use crate::traceroute;
+use futures::future::join_all;
+use std::collections::VecDeque;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use std::{net::Ipv4Addr, sync::atomic::Ordering};
-use tokio::time::{delay_for, timeout, Duration};
+use tokio::spawn;
+use tokio::time::{delay_for, Duration};
 
 #[derive(Debug, Clone)]
 pub struct CancelToken(Arc<AtomicBool>);
@@ -22,6 +29,75 @@ impl CancelToken {
     }
 }
 
+/// Checks if any of the parents of a device are down, making the device unreachable
+async fn parent_down(devices: &Arc<Devices>, ping: &mut Ping, parents: &[DeviceId]) -> bool {
+    for &parent in parents {
+        let parent = match devices.device(parent) {
+            Some(parent) => parent,
+            None => continue,
+        };
+
+        match parent.icmpv4.lock().status.map(|s| s.0) {
+            Some(ServiceStatus::Down) | Some(ServiceStatus::Unreachable) => return true,
+            _ => (),
+        }
+
+        // The parent may not have been detected as down yet, so check it directly
+        let ip = parent.conf.lock().ipv4;
+        if let Some(ip) = ip {
+            let mut up = false;
+            for _ in 0..3i32 {
+                if ping.ping(ip).await.is_ok() {
+                    up = true;
+                    break;
+                }
+            }
+            if !up {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+fn quality(probes: &VecDeque<Option<Duration>>) -> Option<Quality> {
+    if probes.is_empty() {
+        return None;
+    }
+
+    let answered: Vec<f64> = probes
+        .iter()
+        .filter_map(|probe| probe.map(|rtt| rtt.as_secs_f64() * 1000.0))
+        .collect();
+
+    let rtt = if answered.is_empty() {
+        None
+    } else {
+        Some(answered.iter().sum::<f64>() / answered.len() as f64)
+    };
+
+    let lost = probes.len() - answered.len();
+
+    Some(Quality {
+        rtt,
+        loss: lost as f64 * 100.0 / probes.len() as f64,
+    })
+}
+
+/// Sends `burst.count` pings `burst.interval` milliseconds apart
+async fn burst(ping: &Ping, ip: Ipv4Addr, burst: &Burst) -> Vec<Result<Duration, PingError>> {
+    let pings = (0..burst.count.max(1)).map(|i| {
+        let mut ping = ping.clone();
+        let delay = Duration::from_millis(burst.interval * i as u64);
+        async move {
+            delay_for(delay).await;
+            ping.ping(ip).await
+        }
+    });
+    join_all(pings).await
+}
+
 pub async fn device_monitor(
     devices: Arc<Devices>,
     device: Arc<Device>,
@@ -30,40 +106,163 @@ pub async fn device_monitor(
 ) {
     let id = device.conf.lock().id;
     let mut ping = devices.ping.clone();
-    let mut status = device.icmpv4.lock().status;
+    let (mut status, mut flapping, mut maintenance) = {
+        let icmpv4 = device.icmpv4.lock();
+        (icmpv4.status, icmpv4.flapping, icmpv4.maintenance)
+    };
+    // The status when the current maintenance window started
+    let mut maintenance_status = status;
+    let mut transitions = VecDeque::new();
+    let mut probes = VecDeque::new();
+    let mut last_trace: Option<Instant> = None;
 
     loop {
-        let new_status = match timeout(Duration::from_secs(1), ping.ping(ip)).await {
-            Ok(_) => ServiceStatus::Up,
-            Err(_) => {
-                let mut new_status = ServiceStatus::Down;
-
-                // Ping timed out, try 10 times before registering the device as down
-                for _ in 0..10i32 {
-                    delay_for(Duration::from_secs(1)).await;
-                    match timeout(Duration::from_secs(1), ping.ping(ip)).await {
-                        Ok(_) => {
-                            new_status = ServiceStatus::Up;
-                            break;
-                        }
-                        Err(_) => {}
-                    }
+        if device.conf.lock().traceroute {
+            let interval = devices.conf.lock().traceroute_interval;
+            let due = last_trace
+                .map(|last| Instant::now().saturating_duration_since(last).as_secs() >= interval)
+                .unwrap_or(true);
+            if due {
+                last_trace = Some(Instant::now());
+                spawn(traceroute::trace_device(devices.clone(), device.clone()));
+            }
+        }
+
+        let (thresholds, burst_conf) = {
+            let conf = device.conf.lock();
+            (conf.degraded.clone(), conf.burst.clone())
+        };
+        let burst_conf = burst_conf.unwrap_or_else(|| devices.conf.lock().burst.clone());
+        let window = thresholds
+            .as_ref()
+            .map(|thresholds| thresholds.probes.max(1))
+            .unwrap_or(10);
+
+        // The last failed ping, explaining why the device is down
+        let mut reason = None;
+
+        let mut probe = |result: Result<Duration, PingError>| {
+            probes.push_back(result.as_ref().ok().copied());
+            while probes.len() > window {
+                probes.pop_front();
+            }
+            match result {
+                Ok(_) => true,
+                Err(error) => {
+                    reason = Some(error);
+                    false
+                }
+            }
+        };
+
+        let results = burst(&ping, ip, &burst_conf).await;
+        let rtts: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().ok().copied())
+            .collect();
+        let round = Round::new(SystemTime::now(), &rtts);
+        let mut answered = false;
+        for result in results {
+            answered |= probe(result);
+        }
+
+        let new_status = if answered {
+            ServiceStatus::Up
+        } else {
+            let mut new_status = ServiceStatus::Down;
+
+            // Ping failed, try 10 times before registering the device as down
+            for _ in 0..10i32 {
+                delay_for(Duration::from_secs(1)).await;
+                if probe(ping.ping(ip).await) {
+                    new_status = ServiceStatus::Up;
+                    break;
                 }
+            }
 
-                new_status
+            if new_status == ServiceStatus::Down {
+                let mut parents = device.conf.lock().parents.clone();
+                parents.retain(|&parent| parent != id);
+                if parent_down(&devices, &mut ping, &parents).await {
+                    new_status = ServiceStatus::Unreachable;
+                }
             }
+
+            new_status
         };
 
         if cancel.cancelled() {
             break;
         }
 
-        if status.map(|s| s.0) != Some(new_status) {
-            let time = SystemTime::now();
+        let reason = if new_status == ServiceStatus::Up {
+            None
+        } else {
+            reason
+        };
+
+        let quality = quality(&probes);
+
+        {
+            let mut icmpv4 = device.icmpv4.lock();
+            if cancel.cancelled() {
+                break;
+            }
+            icmpv4.quality = quality;
+            icmpv4.round = Some(round);
+            icmpv4.reason = reason.clone();
+        }
 
-            let new_status = Some((new_status, time));
+        devices.history.record_round(id, round);
 
-            let change = {
+        let new_status = match (new_status, &thresholds, quality) {
+            (ServiceStatus::Up, Some(thresholds), Some(quality)) if quality.exceeds(thresholds) => {
+                ServiceStatus::Degraded
+            }
+            _ => new_status,
+        };
+
+        let time = SystemTime::now();
+        let changed = status.map(|s| s.0) != Some(new_status);
+
+        let flap_detection = device
+            .conf
+            .lock()
+            .flap_detection
+            .clone()
+            .unwrap_or_else(|| devices.conf.lock().flap_detection.clone());
+
+        while let Some(&first) = transitions.front() {
+            match time.duration_since(first) {
+                Ok(age) if age.as_secs() >= flap_detection.window => {
+                    transitions.pop_front();
+                }
+                _ => break,
+            }
+        }
+
+        if changed && status.is_some() {
+            transitions.push_back(time);
+        }
+
+        let new_flapping = if flap_detection.transitions == 0 {
+            false
+        } else if flapping {
+            !transitions.is_empty()
+        } else {
+            transitions.len() > flap_detection.transitions
+        };
+
+        let new_maintenance = devices.in_maintenance(id, time);
+
+        if changed || new_flapping != flapping || new_maintenance != maintenance {
+            let new_status = if changed {
+                Some((new_status, time))
+            } else {
+                status
+            };
+
+            let changes = {
                 let mut icmpv4 = device.icmpv4.lock();
 
                 // Check that we're not cancelled in the lock, so we have permission to update the device
@@ -72,21 +271,82 @@ pub async fn device_monitor(
                 }
 
                 icmpv4.status = new_status;
+                icmpv4.flapping = new_flapping;
+                icmpv4.maintenance = new_maintenance;
 
-                let change = DeviceChange::IPv4Status {
-                    device: id,
-                    old: status,
-                    new: new_status,
-                };
+                // Maintenance is applied first so notifications for the status change are suppressed
+                let mut changes = Vec::new();
+
+                if new_maintenance != maintenance {
+                    changes.push(DeviceChange::IPv4Maintenance {
+                        device: id,
+                        maintenance: new_maintenance,
+                    });
+                }
+
+                // The first status isn't notified, so it's where a new device starts from
+                if new_maintenance && !maintenance {
+                    maintenance_status = status.or(new_status);
+                }
+
+                // A status reached during maintenance wasn't notified, so it is when maintenance
+                // ends if the device still isn't up
+                let unnotified = maintenance
+                    && !new_maintenance
+                    && !changed
+                    && new_status != maintenance_status
+                    && new_status.map(|s| s.0) != Some(ServiceStatus::Up);
+                if unnotified {
+                    changes.push(DeviceChange::IPv4Status {
+                        device: id,
+                        old: maintenance_status,
+                        new: new_status,
+                        quality,
+                        reason: reason.clone(),
+                    });
+                }
+
+                if changed {
+                    devices.history.record(Transition {
+                        device: id,
+                        status: new_status.unwrap().0,
+                        time,
+                        maintenance: new_maintenance,
+                        quality,
+                    });
+
+                    changes.push(DeviceChange::IPv4Status {
+                        device: id,
+                        old: status,
+                        new: new_status,
+                        quality,
+                        reason: reason.clone(),
+                    });
+                }
 
-                devices.changes.send(change.clone()).ok();
+                if new_flapping != flapping {
+                    changes.push(DeviceChange::IPv4Flapping {
+                        device: id,
+                        flapping: new_flapping,
+                        status: new_status,
+                        time,
+                    });
+                }
 
-                change
+                for change in &changes {
+                    devices.changes.send(change.clone()).ok();
+                }
+
+                changes
             };
 
-            devices.notify(change).await;
+            for change in changes {
+                devices.notify(change).await;
+            }
 
             status = new_status;
+            flapping = new_flapping;
+            maintenance = new_maintenance;
         }
 
         delay_for(Duration::from_millis(10000)).await;
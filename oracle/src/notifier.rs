@@ -1,45 +1,204 @@
 use crate::devices::Devices;
-use crate::devices::ServiceStatus;
+use crate::devices::{ServiceStatus, Severity};
 use crate::log::Kind;
 use crate::log::Log;
-use crate::{
-    devices::{DeviceChange, DeviceId},
-    state::Conf,
-};
+use crate::{devices::DeviceChange, state::Conf};
 use chrono::{DateTime, Local};
 use lettre::{
     smtp::authentication::Credentials, ClientSecurity, ClientTlsParameters, SmtpClient, Transport,
 };
 use lettre_email::{EmailBuilder, Mailbox};
 use native_tls::{Protocol, TlsConnector};
-use std::{
-    sync::Arc,
-    time::{Instant, SystemTime},
-};
+use std::{sync::Arc, time::SystemTime};
 use tokio::sync::mpsc;
-use tokio::time::{delay_for, Duration};
+use tokio::time::{delay_for, Duration, Instant};
 use tokio::{spawn, task};
 
+fn verb(status: ServiceStatus) -> &'static str {
+    match status {
+        ServiceStatus::Up => "up",
+        ServiceStatus::Degraded => "degraded",
+        ServiceStatus::Down => "down",
+        ServiceStatus::Unreachable => "unreachable",
+        ServiceStatus::DnsFailure => "unresolvable",
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    let time: DateTime<Local> = time.into();
+    time.to_rfc2822()
+}
+
 pub fn send_email(
     devices: &Arc<Devices>,
     log: &Arc<Log>,
     conf: &Conf,
     email_receiver: &str,
-    changes: Vec<(DeviceId, (ServiceStatus, SystemTime))>,
+    changes: Vec<DeviceChange>,
 ) -> bool {
     let mut body = "The following network changes were detected:\n\n".to_owned();
+    let mut severity = Severity::Info;
 
     for change in changes {
-        let time: DateTime<Local> = change.1 .1.into();
-        let time = time.to_rfc2822();
-        let verb = match change.1 .0 {
-            ServiceStatus::Up => "up",
-            ServiceStatus::Down => "down",
-        };
-        let desc = devices.device(change.0).conf.lock().desc();
-        body.push_str(&format!(" - Device `{}` went {} at {}\n", desc, verb, time));
+        match change {
+            DeviceChange::IPv4Status {
+                device,
+                new: Some(new),
+                quality,
+                reason,
+                ..
+            } => {
+                severity = severity.max(new.0.severity());
+                let desc = devices.desc(device);
+                body.push_str(&format!(
+                    " - Device `{}` went {} at {}",
+                    desc,
+                    verb(new.0),
+                    format_time(new.1)
+                ));
+                if new.0 == ServiceStatus::DnsFailure {
+                    let error = devices
+                        .device(device)
+                        .and_then(|device| device.icmpv4.lock().dns_error.clone());
+                    if let Some(error) = error {
+                        body.push_str(&format!(": {}", error));
+                    }
+                }
+                if new.0 == ServiceStatus::Degraded {
+                    body.push_str(&format!(" with {}", quality.unwrap_or_default()));
+                }
+                if new.0 == ServiceStatus::Down {
+                    if let Some(reason) = reason {
+                        body.push_str(&format!(": {}", reason));
+                    }
+                    let children = devices.unreachable_children(device);
+                    if children > 0 {
+                        body.push_str(&format!(
+                            " ({} devices behind it are unreachable)",
+                            children
+                        ));
+                    }
+                    let trace = devices
+                        .device(device)
+                        .and_then(|device| device.icmpv4.lock().trace.clone());
+                    if let Some(trace) = trace {
+                        body.push_str(&format!("\n   Last traceroute:\n{}", trace));
+                    }
+                }
+            }
+            DeviceChange::NewHost {
+                mac,
+                ipv4,
+                hostname,
+                time,
+            } => {
+                severity = severity.max(Severity::Warning);
+                body.push_str(&format!(
+                    " - New host `{}` appeared at {} at {}",
+                    mac,
+                    ipv4,
+                    format_time(time)
+                ));
+                if let Some(hostname) = hostname {
+                    body.push_str(&format!(", calling itself {}", hostname));
+                }
+            }
+            DeviceChange::HostMoved {
+                mac,
+                old,
+                new,
+                time,
+            } => {
+                severity = severity.max(Severity::Warning);
+                body.push_str(&format!(
+                    " - Host `{}` changed address from {} to {} at {}",
+                    mac,
+                    old,
+                    new,
+                    format_time(time)
+                ));
+            }
+            DeviceChange::DnsStatus {
+                device,
+                new: Some(new),
+                rtt,
+                error,
+                ..
+            } => {
+                severity = severity.max(new.0.severity());
+                let desc = devices.desc(device);
+                let check = devices.device(device).and_then(|device| {
+                    let conf = device.conf.lock();
+                    conf.dns.as_ref().map(|dns| dns.name.clone())
+                });
+                body.push_str(&format!(
+                    " - DNS check for `{}` on device `{}` went {} at {}",
+                    check.unwrap_or_default(),
+                    desc,
+                    verb(new.0),
+                    format_time(new.1)
+                ));
+                if let Some(error) = error {
+                    body.push_str(&format!(": {}", error));
+                } else if new.0 == ServiceStatus::Degraded {
+                    body.push_str(&format!(" with {:.2} ms", rtt.unwrap_or_default()));
+                }
+            }
+            DeviceChange::IPv4Flapping {
+                device,
+                flapping,
+                status,
+                time,
+            } => {
+                severity = severity.max(Severity::Warning);
+                let desc = devices.desc(device);
+                if flapping {
+                    body.push_str(&format!(
+                        " - Device `{}` started flapping at {}",
+                        desc,
+                        format_time(time)
+                    ));
+                } else {
+                    body.push_str(&format!(
+                        " - Device `{}` stopped flapping at {}",
+                        desc,
+                        format_time(time)
+                    ));
+                    if let Some(status) = status {
+                        body.push_str(&format!(" and is {}", verb(status.0)));
+                    }
+                }
+            }
+            _ => continue,
+        }
+        body.push('\n');
     }
 
+    let subject = match severity {
+        Severity::Info => "Network changes".to_owned(),
+        severity => format!("[{:?}] Network changes", severity),
+    };
+    deliver_email(log, conf, email_receiver, subject, body)
+}
+
+/// Sends an email showing that notifications reach `email_receiver`
+pub fn send_test_email(log: &Arc<Log>, conf: &Conf, email_receiver: &str) -> bool {
+    deliver_email(
+        log,
+        conf,
+        email_receiver,
+        "Test notification".to_owned(),
+        "This is a test of the network change notifications.\n".to_owned(),
+    )
+}
+
+fn deliver_email(
+    log: &Arc<Log>,
+    conf: &Conf,
+    email_receiver: &str,
+    subject: String,
+    body: String,
+) -> bool {
     let smtp = conf.lock().smtp.clone().unwrap();
 
     let from = match smtp.from.parse::<Mailbox>() {
@@ -67,7 +226,7 @@ pub fn send_email(
     let email = EmailBuilder::new()
         .from(from)
         .to(to)
-        .subject("Network changes")
+        .subject(subject)
         .body(body)
         .build();
 
@@ -127,14 +286,11 @@ pub async fn generate_email_signal(
         {
             let mut lock = devices.last_email.lock();
 
-            match *lock {
-                Some(last) => {
-                    if Instant::now().saturating_duration_since(last).as_secs() > 30 {
-                        *lock = None;
-                        break;
-                    }
+            if let Some(last) = *lock {
+                if Instant::now().saturating_duration_since(last).as_secs() > 30 {
+                    *lock = None;
+                    break;
                 }
-                None => (),
             }
         };
 
@@ -144,30 +300,38 @@ pub async fn generate_email_signal(
     send_email_signal.send(()).await.unwrap();
 }
 
+/// Delivers a batch of changes, returning whether it succeeded
+pub type Deliver = Arc<dyn Fn(Vec<DeviceChange>) -> bool + Send + Sync>;
+
+pub fn email(conf: Conf, devices: Arc<Devices>, log: Arc<Log>, email_receiver: String) -> Deliver {
+    Arc::new(move |changes| send_email(&devices, &log, &conf, &email_receiver, changes))
+}
+
 pub async fn notifier(
-    conf: Conf,
     devices: Arc<Devices>,
     log: Arc<Log>,
-    email_receiver: String,
+    name: String,
+    deliver: Deliver,
     mut receiver: mpsc::Receiver<DeviceChange>,
 ) {
     let mut buffer = Vec::new();
     let mut active = false;
     let (send_email_signal, mut email_signal) = mpsc::channel(10);
 
-    log.note(&format!("Notifier for {} starting", email_receiver));
+    log.note(&format!("Notifier for {} starting", name));
 
     loop {
         tokio::select! {
             Some(change) = receiver.recv() => {
-                let (device, status) = match change {
-                    DeviceChange::IPv4Status { device, old: Some(_), new: Some(new) } => {
-                        (device, new)
-                    }
+                match change {
+                    DeviceChange::IPv4Status { old: Some(_), new: Some(_), .. } => (),
+                    DeviceChange::IPv4Flapping { .. } => (),
+                    DeviceChange::DnsStatus { old: Some(_), new: Some(_), .. } => (),
+                    DeviceChange::NewHost { .. } | DeviceChange::HostMoved { .. } => (),
                     _ => continue,
                 };
 
-                buffer.push((device, status));
+                buffer.push(change);
 
                 if !active {
                     active = true;
@@ -177,13 +341,9 @@ pub async fn notifier(
             Some(()) = email_signal.recv() => {
                 let result = {
                     let buffer = buffer.clone();
-                    let log = log.clone();
-                    let conf = conf.clone();
-                    let devices = devices.clone();
-                    let email_receiver = email_receiver.clone();
-
-                    task::spawn_blocking(move || send_email(&devices, &log, &conf, &email_receiver, buffer)).await.unwrap()
+                    let deliver = deliver.clone();
 
+                    task::spawn_blocking(move || deliver(buffer)).await.unwrap()
                 };
 
                 // Return email token
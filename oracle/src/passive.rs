@@ -0,0 +1,382 @@
+//! Learns about hosts from the kernel's neighbour table and DHCP lease files
+
+use crate::devices::Devices;
+use crate::discovery::{self, Candidate};
+use crate::hosts;
+use crate::mac::MacAddr;
+use crate::state::Passive;
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{delay_for, Duration};
+
+/// A host seen on the network
+#[derive(Debug, Clone, PartialEq)]
+pub struct Neighbour {
+    pub ipv4: Ipv4Addr,
+    pub mac: MacAddr,
+    pub hostname: Option<String>,
+}
+
+/// Parses `/proc/net/arp`, skipping incomplete entries
+fn parse_arp(table: &str) -> Vec<Neighbour> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let parts: Vec<_> = line.split_whitespace().collect();
+            let flags = u32::from_str_radix(parts.get(2)?.trim_start_matches("0x"), 16).ok()?;
+            let mac: MacAddr = parts.get(3)?.parse().ok()?;
+            if flags == 0 || mac == MacAddr::default() {
+                return None;
+            }
+            Some(Neighbour {
+                ipv4: parts.first()?.parse().ok()?,
+                mac,
+                hostname: None,
+            })
+        })
+        .collect()
+}
+
+/// Parses a dnsmasq lease file, with lines of expiry time, MAC, IP, hostname and client id
+fn parse_dnsmasq(leases: &str, now: u64) -> Vec<Neighbour> {
+    leases
+        .lines()
+        .filter_map(|line| {
+            let parts: Vec<_> = line.split_whitespace().collect();
+            let expiry: u64 = parts.first()?.parse().ok()?;
+            if expiry != 0 && expiry < now {
+                return None;
+            }
+            let hostname = parts.get(3).filter(|name| **name != "*");
+            Some(Neighbour {
+                ipv4: parts.get(2)?.parse().ok()?,
+                mac: parts.get(1)?.parse().ok()?,
+                hostname: hostname.map(|name| name.to_string()),
+            })
+        })
+        .collect()
+}
+
+/// Parses an ISC dhcpd lease file. Leases are appended to the file, so the last one
+/// for an address is current.
+fn parse_dhcpd(leases: &str) -> Vec<Neighbour> {
+    let mut neighbours: Vec<Neighbour> = Vec::new();
+    let mut ipv4 = None;
+    let mut mac = None;
+    let mut hostname = None;
+    let mut active = true;
+
+    for line in leases.lines() {
+        let line = line.trim().trim_end_matches(';');
+        let parts: Vec<_> = line.split_whitespace().collect();
+
+        match parts.as_slice() {
+            ["lease", ip, "{"] => {
+                ipv4 = ip.parse().ok();
+                mac = None;
+                hostname = None;
+                active = true;
+            }
+            ["hardware", "ethernet", address] => mac = address.parse().ok(),
+            ["client-hostname", name] => hostname = Some(name.trim_matches('"').to_owned()),
+            ["binding", "state", state] => active = *state == "active",
+            ["}"] => {
+                if let Some(ip) = ipv4.take() {
+                    neighbours.retain(|neighbour| neighbour.ipv4 != ip);
+                    if let (Some(mac), true) = (mac, active) {
+                        neighbours.push(Neighbour {
+                            ipv4: ip,
+                            mac,
+                            hostname: hostname.take(),
+                        });
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    neighbours
+}
+
+fn parse_leases(leases: &str, now: u64) -> Vec<Neighbour> {
+    if leases
+        .lines()
+        .any(|line| line.trim_start().starts_with("lease "))
+    {
+        parse_dhcpd(leases)
+    } else {
+        parse_dnsmasq(leases, now)
+    }
+}
+
+/// Reads the neighbour table and lease files. Leases name hosts in the neighbour table, but
+/// don't override the address the neighbour table has for a MAC address.
+pub fn neighbours(conf: &Passive) -> Vec<Neighbour> {
+    let mut neighbours = parse_arp(&fs::read_to_string("/proc/net/arp").unwrap_or_default());
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+
+    for file in &conf.lease_files {
+        let leases = match fs::read_to_string(file) {
+            Ok(leases) => leases,
+            Err(_) => continue,
+        };
+
+        for lease in parse_leases(&leases, now) {
+            match neighbours.iter_mut().find(|n| n.mac == lease.mac) {
+                Some(neighbour) => {
+                    if neighbour.ipv4 == lease.ipv4 && neighbour.hostname.is_none() {
+                        neighbour.hostname = lease.hostname;
+                    }
+                }
+                None => neighbours.push(lease),
+            }
+        }
+    }
+
+    neighbours
+}
+
+/// Updates the address of the device with the neighbour's MAC address, unless it's among the
+/// `addresses` seen with that MAC address, or adds the neighbour as a candidate. Returns
+/// whether a device changed.
+fn observed(devices: &Arc<Devices>, neighbour: Neighbour, addresses: &[Ipv4Addr]) -> bool {
+    let device = devices.find(|conf| conf.mac == Some(neighbour.mac));
+
+    if let Some(conf) = device {
+        let mut changed = false;
+        devices.update(conf.id, |conf| {
+            // Devices with a hostname get their address from DNS
+            if conf.mac != Some(neighbour.mac)
+                || conf.ipv4.is_some_and(|ipv4| addresses.contains(&ipv4))
+                || conf.hostname.is_some()
+            {
+                return;
+            }
+
+            devices.log.note(&format!(
+                "Device {} changed address to {} ({} was seen with it)",
+                conf.desc(),
+                neighbour.ipv4,
+                neighbour.mac
+            ));
+            conf.ipv4 = Some(neighbour.ipv4);
+            changed = true;
+        });
+        return changed;
+    }
+
+    if discovery::known(devices, neighbour.ipv4).is_some() {
+        return false;
+    }
+
+    let desc = match &neighbour.hostname {
+        Some(hostname) => format!("{} ({}, {})", hostname, neighbour.ipv4, neighbour.mac),
+        None => format!("{} ({})", neighbour.ipv4, neighbour.mac),
+    };
+    let new = devices.discovery.found(Candidate {
+        ipv4: neighbour.ipv4,
+        mac: Some(neighbour.mac),
+        hostname: neighbour.hostname,
+        services: Vec::new(),
+        rtt: None,
+        seen: SystemTime::now(),
+    });
+    if new {
+        devices.log.note(&format!("New host {} found", desc));
+    }
+
+    false
+}
+
+/// Reads the neighbour table and lease files once
+pub async fn scan(devices: &Arc<Devices>) {
+    let conf = devices.conf.lock().passive.clone();
+    let neighbours = neighbours(&conf);
+
+    hosts::observe(devices, &neighbours).await;
+    observe_all(devices, neighbours);
+}
+
+/// Observes the neighbours of a scan and saves the devices if any changed
+fn observe_all(devices: &Arc<Devices>, neighbours: Vec<Neighbour>) {
+    // A host may answer on several addresses, such as with proxy ARP, which mustn't move
+    // its device between them
+    let mut addresses: HashMap<MacAddr, Vec<Ipv4Addr>> = HashMap::new();
+    for neighbour in &neighbours {
+        addresses
+            .entry(neighbour.mac)
+            .or_default()
+            .push(neighbour.ipv4);
+    }
+
+    let mut changed = false;
+    for neighbour in neighbours {
+        let addresses = &addresses[&neighbour.mac];
+        changed |= observed(devices, neighbour, addresses);
+    }
+    if changed {
+        devices.save_logged();
+    }
+}
+
+/// Periodically reads the neighbour table and lease files
+pub async fn watcher(devices: Arc<Devices>) {
+    loop {
+        let interval = devices.conf.lock().passive.interval;
+        if interval == 0 {
+            delay_for(Duration::from_secs(60)).await;
+            continue;
+        }
+
+        scan(&devices).await;
+
+        delay_for(Duration::from_secs(interval)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::DeviceConf;
+    use crate::tests::{devices_in, temp_dir};
+
+    fn neighbour(ip: &str, mac: &str, hostname: Option<&str>) -> Neighbour {
+        Neighbour {
+            ipv4: ip.parse().unwrap(),
+            mac: mac.parse().unwrap(),
+            hostname: hostname.map(|name| name.to_owned()),
+        }
+    }
+
+    #[test]
+    fn arp() {
+        let table = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         a4:91:b1:0c:22:7e     *        eth0
+192.168.1.23     0x1         0x0         00:00:00:00:00:00     *        eth0
+192.168.1.40     0x1         0x0         3c:22:fb:11:90:02     *        eth0
+192.168.1.41     0x1         0x6         3c:22:fb:11:90:03     *        eth0
+192.168.1.42     0x1         0x2         00:00:00:00:00:00     *        eth0
+garbage
+";
+        assert_eq!(
+            parse_arp(table),
+            vec![
+                neighbour("192.168.1.1", "a4:91:b1:0c:22:7e", None),
+                neighbour("192.168.1.41", "3c:22:fb:11:90:03", None),
+            ]
+        );
+        assert!(parse_arp("").is_empty());
+    }
+
+    #[test]
+    fn dnsmasq() {
+        let leases = "\
+1800000100 b8:27:eb:4d:1a:02 192.168.1.50 raspberrypi 01:b8:27:eb:4d:1a:02
+1700000000 b8:27:eb:4d:1a:03 192.168.1.51 expired *
+1800000200 b8:27:eb:4d:1a:04 192.168.1.52 * *
+0 b8:27:eb:4d:1a:05 192.168.1.53 static *
+1800000300 not-a-mac 192.168.1.54 broken *
+";
+        assert_eq!(
+            parse_leases(leases, 1_750_000_000),
+            vec![
+                neighbour("192.168.1.50", "b8:27:eb:4d:1a:02", Some("raspberrypi")),
+                neighbour("192.168.1.52", "b8:27:eb:4d:1a:04", None),
+                neighbour("192.168.1.53", "b8:27:eb:4d:1a:05", Some("static")),
+            ]
+        );
+    }
+
+    #[test]
+    fn dhcpd() {
+        let leases = r#"
+# The format of this file is documented in the dhcpd.leases(5) manual page.
+authoring-byte-order little-endian;
+
+lease 10.0.0.10 {
+  starts 3 2026/10/14 08:00:00;
+  ends 3 2026/10/14 20:00:00;
+  binding state active;
+  next binding state free;
+  rewind binding state free;
+  hardware ethernet 00:11:22:33:44:01;
+  client-hostname "laptop";
+}
+lease 10.0.0.11 {
+  binding state active;
+  hardware ethernet 00:11:22:33:44:02;
+  client-hostname "phone";
+}
+lease 10.0.0.12 {
+  binding state free;
+  hardware ethernet 00:11:22:33:44:03;
+}
+lease 10.0.0.11 {
+  binding state free;
+  hardware ethernet 00:11:22:33:44:02;
+  client-hostname "phone";
+}
+lease 10.0.0.10 {
+  binding state active;
+  hardware ethernet 00:11:22:33:44:04;
+}
+lease 10.0.0.13 {
+  binding state active;
+}
+"#;
+        // The later lease for 10.0.0.10 supersedes the earlier one and 10.0.0.11 was freed
+        assert_eq!(
+            parse_leases(leases, 0),
+            vec![neighbour("10.0.0.10", "00:11:22:33:44:04", None)]
+        );
+    }
+
+    #[tokio::test]
+    async fn device_addresses() {
+        let dir = temp_dir("passive");
+        let devices = devices_in(&dir);
+        devices.add(DeviceConf {
+            id: 0,
+            ipv4: Some("10.0.0.1".parse().unwrap()),
+            mac: Some("02:00:00:00:00:01".parse().unwrap()),
+            ..Default::default()
+        });
+        let ipv4 = || devices.confs()[0].ipv4.unwrap().to_string();
+        let mut entries = devices.log.subscribe();
+
+        // A host answering on several addresses stays where it is
+        for _ in 0..3 {
+            observe_all(
+                &devices,
+                vec![
+                    neighbour("10.0.0.2", "02:00:00:00:00:01", None),
+                    neighbour("10.0.0.1", "02:00:00:00:00:01", None),
+                ],
+            );
+            assert_eq!(ipv4(), "10.0.0.1");
+        }
+        assert!(entries.try_recv().is_err());
+
+        observe_all(
+            &devices,
+            vec![
+                neighbour("10.0.0.3", "02:00:00:00:00:01", None),
+                neighbour("10.0.0.2", "02:00:00:00:00:01", None),
+            ],
+        );
+        assert_eq!(ipv4(), "10.0.0.3");
+        assert!(entries.try_recv().unwrap().msg.contains("changed address"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+//! Crash-safe writes of the data files with rotated backups
+
+use crate::log::{Kind, Log};
+use parking_lot::{const_mutex, Mutex};
+use serde::de::DeserializeOwned;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The number of previous versions kept as `<file>.1` (newest) to `<file>.<BACKUPS>`
+const BACKUPS: usize = 5;
+
+/// Serializes writes, which share the temporary file and backups
+static WRITING: Mutex<()> = const_mutex(());
+
+/// `path` with `suffix` appended to the file name
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn backup(path: &Path, n: usize) -> PathBuf {
+    suffixed(path, &format!(".{}", n))
+}
+
+/// Replaces the file at `path` without leaving a partially written file behind on a crash.
+/// The contents are written to a temporary file and synced before it's renamed over `path`,
+/// after the current file is copied to the first backup.
+pub fn write(path: &Path, contents: &str) -> io::Result<()> {
+    let _writing = WRITING.lock();
+    let temp = suffixed(path, ".tmp");
+
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    })();
+    if let Err(error) = result {
+        fs::remove_file(&temp).ok();
+        return Err(error);
+    }
+
+    if path.exists() {
+        for n in (1..BACKUPS).rev() {
+            if backup(path, n).exists() {
+                fs::rename(backup(path, n), backup(path, n + 1))?;
+            }
+        }
+        fs::copy(path, backup(path, 1))?;
+    }
+
+    fs::rename(&temp, path)?;
+
+    // Sync the directory so the rename survives a crash
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+fn read<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let contents = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    serde_json::from_str(&contents).map_err(|error| error.to_string())
+}
+
+/// Reads the file at `path`, or the newest backup which parses if it can't be read.
+/// The unreadable file is moved to `<file>.corrupt` so it doesn't replace a valid backup
+/// on the next write.
+pub fn load<T: DeserializeOwned>(path: &Path, log: &Log) -> Result<T, String> {
+    let error = match read(path) {
+        Ok(value) => return Ok(value),
+        Err(error) => error,
+    };
+
+    for n in 1..=BACKUPS {
+        if let Ok(value) = read(&backup(path, n)) {
+            if path.exists() {
+                fs::rename(path, suffixed(path, ".corrupt")).ok();
+            }
+            fs::copy(backup(path, n), path).ok();
+            log.log(
+                Kind::Error,
+                &format!(
+                    "Recovered {} from {} after failing to read it: {}",
+                    path.display(),
+                    backup(path, n).display(),
+                    error
+                ),
+            );
+            return Ok(value);
+        }
+    }
+
+    Err(format!("Unable to read {}: {}", path.display(), error))
+}
+
+/// Like `load`, but `None` if neither the file nor any backup exists yet
+pub fn load_optional<T: DeserializeOwned>(path: &Path, log: &Log) -> Result<Option<T>, String> {
+    if !path.exists() && !(1..=BACKUPS).any(|n| backup(path, n).exists()) {
+        return Ok(None);
+    }
+    load(path, log).map(Some)
+}
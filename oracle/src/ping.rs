@@ -1,112 +1,394 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use rand;
+use serde::{Deserialize, Serialize};
 use socket2::{Domain, Protocol, Socket, Type};
-use std::io::Cursor;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Cursor};
 use std::io::{Seek, SeekFrom};
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::net::{SocketAddrV4, SocketAddrV6};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc as std_mpsc;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use std::{collections::HashMap, time::Instant};
-use tokio::runtime::Handle;
 use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// ICMP Destination Unreachable codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Unreachable {
+    Network,
+    Host,
+    Protocol,
+    Port,
+    FragmentationNeeded,
+    SourceRouteFailed,
+    NetworkUnknown,
+    HostUnknown,
+    AdminProhibited,
+    Other(u8),
+}
+
+impl Unreachable {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => Unreachable::Network,
+            1 => Unreachable::Host,
+            2 => Unreachable::Protocol,
+            3 => Unreachable::Port,
+            4 => Unreachable::FragmentationNeeded,
+            5 => Unreachable::SourceRouteFailed,
+            6 => Unreachable::NetworkUnknown,
+            7 => Unreachable::HostUnknown,
+            9 | 10 | 13 => Unreachable::AdminProhibited,
+            code => Unreachable::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unreachable::Network => write!(f, "network unreachable"),
+            Unreachable::Host => write!(f, "host unreachable"),
+            Unreachable::Protocol => write!(f, "protocol unreachable"),
+            Unreachable::Port => write!(f, "port unreachable"),
+            Unreachable::FragmentationNeeded => write!(f, "fragmentation needed"),
+            Unreachable::SourceRouteFailed => write!(f, "source route failed"),
+            Unreachable::NetworkUnknown => write!(f, "network unknown"),
+            Unreachable::HostUnknown => write!(f, "host unknown"),
+            Unreachable::AdminProhibited => write!(f, "administratively prohibited"),
+            Unreachable::Other(code) => write!(f, "destination unreachable (code {})", code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    /// An echo reply from the target
+    Echo,
+    /// A router reported that the TTL was exceeded
+    TimeExceeded(Ipv4Addr),
+    /// A router reported that the target can't be reached
+    Unreachable(Unreachable, Ipv4Addr),
+}
+
+/// Why a ping didn't get an echo reply
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PingError {
+    Unreachable {
+        code: Unreachable,
+        router: Ipv4Addr,
+    },
+    /// The TTL was exceeded, likely due to a routing loop
+    TimeExceeded {
+        router: Ipv4Addr,
+    },
+    Timeout,
+    /// The echo request couldn't be sent
+    Send(String),
+    /// The ICMP socket couldn't be opened or the ping task has stopped
+    Unavailable(String),
+}
+
+impl fmt::Display for PingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PingError::Unreachable { code, router } => {
+                write!(f, "{} (reported by {})", code, router)
+            }
+            PingError::TimeExceeded { router } => {
+                write!(f, "TTL exceeded (reported by {})", router)
+            }
+            PingError::Timeout => write!(f, "no reply"),
+            PingError::Send(error) => write!(f, "unable to send ping: {}", error),
+            PingError::Unavailable(error) => write!(f, "pinging is unavailable: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for PingError {}
+
+/// An ICMP message which is a response to one of our echo requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpReply {
+    Echo {
+        id: u16,
+        seq: u16,
+    },
+    TimeExceeded {
+        id: u16,
+        seq: u16,
+        dst: Ipv4Addr,
+    },
+    Unreachable {
+        id: u16,
+        seq: u16,
+        dst: Ipv4Addr,
+        code: u8,
+    },
+}
+
+/// Something which happened to an echo request sent by a `Transport`
+#[derive(Debug)]
+pub enum Event {
+    /// An ICMP reply from `Ipv4Addr` received at `Instant`
+    Reply(Ipv4Addr, IcmpReply, Instant),
+    /// The echo request with the sequence number couldn't be sent
+    SendFailed(u16, String),
+}
+
+pub type Events = mpsc::UnboundedSender<Event>;
+
+/// Sends echo requests and reports replies to them as `Event`s
+pub trait Transport: Send + 'static {
+    /// Sends an echo request without blocking. A `ttl` of `None` uses the default TTL.
+    fn send(&mut self, ip: Ipv4Addr, id: u16, seq: u16, ttl: Option<u32>);
+}
+
+/// A raw ICMP socket with threads for sending and receiving, which stop when it's dropped
+pub struct IcmpSocket {
+    requests: std_mpsc::Sender<(Ipv4Addr, u16, u16, Option<u32>)>,
+    events: Events,
+    stop: Arc<AtomicBool>,
+}
+
+impl IcmpSocket {
+    pub fn open(events: Events) -> io::Result<Self> {
+        let socket = Arc::new(Socket::new(
+            Domain::ipv4(),
+            Type::raw(),
+            Some(Protocol::icmpv4()),
+        )?);
+
+        // Wake up the receiving thread regularly so it notices when to stop
+        socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // Create thread to recieve ping replies
+        let socket_ = socket.clone();
+        let stop_ = stop.clone();
+        let events_ = events.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 1500];
+            while !stop_.load(Ordering::SeqCst) {
+                if let Ok((size, src)) = socket_.recv_from(&mut buffer) {
+                    let time = Instant::now();
+                    if let Some(sa) = src.as_inet() {
+                        if let Some(reply) = parse_ping_v4(&buffer[0..size]) {
+                            let event = Event::Reply(sa.ip().to_owned(), reply, time);
+                            if events_.send(event).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+
+        // Create thread to send ping packets, it stops when `requests` is dropped
+        let (requests, rx) = std_mpsc::channel::<(Ipv4Addr, u16, u16, Option<u32>)>();
+        let events_ = events.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 1500];
+            let default_ttl = socket.ttl().unwrap_or(64);
+            let mut current_ttl = default_ttl;
+            while let Ok((ip, id, seq, ttl)) = rx.recv() {
+                let ttl = ttl.unwrap_or(default_ttl);
+                if ttl != current_ttl && socket.set_ttl(ttl).is_ok() {
+                    current_ttl = ttl;
+                }
+                if let Err(error) = send_ping_v4(&socket, &mut buffer, ip, id, seq) {
+                    events_.send(Event::SendFailed(seq, error.to_string())).ok();
+                }
+            }
+        });
+
+        Ok(IcmpSocket {
+            requests,
+            events,
+            stop,
+        })
+    }
+}
+
+impl Transport for IcmpSocket {
+    fn send(&mut self, ip: Ipv4Addr, id: u16, seq: u16, ttl: Option<u32>) {
+        if self.requests.send((ip, id, seq, ttl)).is_err() {
+            let error = "The sending thread has stopped".to_owned();
+            self.events.send(Event::SendFailed(seq, error)).ok();
+        }
+    }
+}
+
+impl Drop for IcmpSocket {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    ip: Ipv4Addr,
+    ttl: Option<u32>,
+    deadline: Instant,
+    reply: oneshot::Sender<Result<(Reply, Duration), PingError>>,
+}
 
 #[derive(Clone)]
 pub struct Ping {
-    tx: mpsc::Sender<(Ipv4Addr, oneshot::Sender<Duration>)>,
+    tx: mpsc::Sender<Request>,
 }
 
 impl Ping {
     pub fn new() -> Self {
+        Self::with_transport(IcmpSocket::open)
+    }
+
+    /// Creates a `Ping` which sends echo requests using the transport returned by `open`
+    pub fn with_transport<T: Transport>(
+        open: impl FnOnce(Events) -> io::Result<T> + Send + 'static,
+    ) -> Self {
         let (tx, rx) = mpsc::channel(1000);
 
-        tokio::spawn(ping_task(rx));
+        tokio::spawn(ping_task(rx, open));
 
         Self { tx }
     }
 
     // TODO: Remove &mut in Tokio 0.3
-    pub async fn ping(&mut self, ip: Ipv4Addr) -> Duration {
+    pub async fn ping(&mut self, ip: Ipv4Addr) -> Result<Duration, PingError> {
+        match self.send(ip, None, TIMEOUT).await? {
+            (Reply::Echo, rtt) => Ok(rtt),
+            (Reply::TimeExceeded(router), _) => Err(PingError::TimeExceeded { router }),
+            (Reply::Unreachable(code, router), _) => Err(PingError::Unreachable { code, router }),
+        }
+    }
+
+    /// Sends an echo request with a limited TTL, which is answered either by the target or
+    /// by the router where the TTL is exceeded or which can't forward it
+    pub async fn probe(
+        &mut self,
+        ip: Ipv4Addr,
+        ttl: u32,
+        timeout: Duration,
+    ) -> Result<(Reply, Duration), PingError> {
+        self.send(ip, Some(ttl), timeout).await
+    }
+
+    async fn send(
+        &mut self,
+        ip: Ipv4Addr,
+        ttl: Option<u32>,
+        timeout: Duration,
+    ) -> Result<(Reply, Duration), PingError> {
+        let stopped = || PingError::Unavailable("The ping task has stopped".to_owned());
         let (tx, rx) = oneshot::channel();
-        self.tx.send((ip, tx)).await.unwrap();
-        rx.await.unwrap()
+        let request = Request {
+            ip,
+            ttl,
+            deadline: Instant::now() + timeout,
+            reply: tx,
+        };
+        self.tx.send(request).await.map_err(|_| stopped())?;
+        rx.await.map_err(|_| stopped())?
     }
 }
 
-async fn ping_task(mut ping_requests: mpsc::Receiver<(Ipv4Addr, oneshot::Sender<Duration>)>) {
-    let socket =
-        Arc::new(Socket::new(Domain::ipv4(), Type::raw(), Some(Protocol::icmpv4())).unwrap());
-
-    // Create thread to recieve ping replies
-    let (mut tx, mut ping_replies) = mpsc::channel(1000);
-    let socket_ = socket.clone();
-    let handle = Handle::current();
-    thread::spawn(move || {
-        let mut buffer = [0; 1500];
-        loop {
-            if let Ok((size, src)) = socket_.recv_from(&mut buffer) {
-                let time = Instant::now();
-                src.as_inet().map(|sa| {
-                    parse_ping_v4(&buffer[0..size]).map(|(id, seq)| {
-                        handle
-                            .block_on(tx.send((sa.ip().to_owned(), id, seq, time)))
-                            .ok();
-                    });
-                });
+async fn ping_task<T: Transport>(
+    mut ping_requests: mpsc::Receiver<Request>,
+    open: impl FnOnce(Events) -> io::Result<T>,
+) {
+    let (events, mut ping_events) = mpsc::unbounded_channel();
+
+    let mut transport = match open(events) {
+        Ok(transport) => transport,
+        Err(error) => {
+            // Fail requests right away instead of letting them wait for their timeout
+            let error = PingError::Unavailable(format!("Unable to open ICMP socket: {}", error));
+            while let Some(request) = ping_requests.recv().await {
+                request.reply.send(Err(error.clone())).ok();
             }
+            return;
         }
-    });
-
-    // Create thread to send ping packets
-    let (mut ping_sender, mut rx) = mpsc::channel(1000);
-    let handle = Handle::current();
-    thread::spawn(move || {
-        let mut buffer = [0; 1500];
-        loop {
-            if let Some((ip, id, seq)) = handle.block_on(rx.recv()) {
-                send_ping_v4(&socket, &mut buffer, ip, id, seq);
-            }
-        }
-    });
+    };
 
     let mut seq = rand::random();
     let id = rand::random();
 
-    let mut map: HashMap<u16, (Ipv4Addr, Instant, oneshot::Sender<Duration>)> = HashMap::new();
+    let mut map: HashMap<u16, (Request, Instant)> = HashMap::new();
 
-    let mut cleanup = tokio::time::interval(Duration::from_secs(600));
+    let mut expire = tokio::time::interval(Duration::from_millis(100));
 
     loop {
         tokio::select! {
-            Some((ip, reply)) = ping_requests.recv() => {
-                map.insert(seq, (ip, Instant::now(), reply));
-                ping_sender.send((ip, id, seq)).await.unwrap();
+            request = ping_requests.recv() => {
+                let request = match request {
+                    Some(request) => request,
+                    None => break,
+                };
+                let (ip, ttl) = (request.ip, request.ttl);
+                map.insert(seq, (request, Instant::now()));
+                transport.send(ip, id, seq, ttl);
                 seq = seq.wrapping_add(1);
             },
-            Some((from, reply_id, seq, time)) = ping_replies.recv() => {
-                if id == reply_id {
-                    map.remove(&seq).map(|(ip, start, reply)| {
-                        if ip == from {
+            Some(event) = ping_events.recv() => match event {
+                Event::SendFailed(seq, error) => {
+                    if let Some((request, _)) = map.remove(&seq) {
+                        request.reply.send(Err(PingError::Send(error))).ok();
+                    }
+                }
+                Event::Reply(from, reply, time) => {
+                    let (reply_id, seq, target, reply) = match reply {
+                        IcmpReply::Echo { id, seq } => (id, seq, from, Reply::Echo),
+                        IcmpReply::TimeExceeded { id, seq, dst } => {
+                            (id, seq, dst, Reply::TimeExceeded(from))
+                        }
+                        IcmpReply::Unreachable { id, seq, dst, code } => {
+                            (id, seq, dst, Reply::Unreachable(Unreachable::from_code(code), from))
+                        }
+                    };
+
+                    let matches = map
+                        .get(&seq)
+                        .map(|(request, _)| request.ip == target)
+                        .unwrap_or(false);
+
+                    if id == reply_id && matches {
+                        if let Some((request, start)) = map.remove(&seq) {
                             let duration = time.saturating_duration_since(start);
-                            reply.send(duration).ok();
+                            request.reply.send(Ok((reply, duration))).ok();
                         }
-                    });
+                    }
                 }
             },
-            _ = cleanup.tick() => {
-                // Clean up pings that didn't get answers
+            _ = expire.tick() => {
                 let now = Instant::now();
-                map.retain(|_, value| {
-                    now.saturating_duration_since(value.1).as_secs() < 600
-                });
+                let expired: Vec<u16> = map
+                    .iter()
+                    .filter(|(_, (request, _))| request.deadline <= now)
+                    .map(|(&seq, _)| seq)
+                    .collect();
+                for seq in expired {
+                    if let Some((request, _)) = map.remove(&seq) {
+                        request.reply.send(Err(PingError::Timeout)).ok();
+                    }
+                }
             },
-            else => { break }
         };
     }
 }
 
-pub fn send_ping_v4(socket: &Socket, buffer: &mut [u8], ip: Ipv4Addr, id: u16, seq: u16) {
+pub fn send_ping_v4(
+    socket: &Socket,
+    buffer: &mut [u8],
+    ip: Ipv4Addr,
+    id: u16,
+    seq: u16,
+) -> io::Result<()> {
     const ECHO_REQUEST_TYPE: u8 = 8;
     const ECHO_REQUEST_CODE: u8 = 0;
 
@@ -114,24 +396,27 @@ pub fn send_ping_v4(socket: &Socket, buffer: &mut [u8], ip: Ipv4Addr, id: u16, s
 
     let mut cursor = Cursor::new(buffer);
 
-    cursor.write_u8(ECHO_REQUEST_TYPE).unwrap();
-    cursor.write_u8(ECHO_REQUEST_CODE).unwrap();
-    cursor.write_u16::<BigEndian>(0).unwrap();
-    cursor.write_u16::<BigEndian>(id).unwrap();
-    cursor.write_u16::<BigEndian>(seq).unwrap();
+    cursor.write_u8(ECHO_REQUEST_TYPE)?;
+    cursor.write_u8(ECHO_REQUEST_CODE)?;
+    cursor.write_u16::<BigEndian>(0)?;
+    cursor.write_u16::<BigEndian>(id)?;
+    cursor.write_u16::<BigEndian>(seq)?;
 
     let pos = cursor.position() as usize;
     let buffer = &mut cursor.into_inner()[0..pos];
 
     write_checksum(buffer, &mut 0);
 
-    // FIXME: Can fail without a network connection (and likely invalid IP too)
-    socket.send_to(buffer, &addr.into()).ok();
+    socket.send_to(buffer, &addr.into())?;
+    Ok(())
 }
 
-pub fn parse_ping_v4(packet: &[u8]) -> Option<(u16, u16)> {
+pub fn parse_ping_v4(packet: &[u8]) -> Option<IcmpReply> {
     const ECHO_REPLY_TYPE: u8 = 0;
     const ECHO_REPLY_CODE: u8 = 0;
+    const DESTINATION_UNREACHABLE_TYPE: u8 = 3;
+    const TIME_EXCEEDED_TYPE: u8 = 11;
+    const TTL_EXCEEDED_CODE: u8 = 0;
 
     let mut cursor = Cursor::new(packet);
 
@@ -139,23 +424,62 @@ pub fn parse_ping_v4(packet: &[u8]) -> Option<(u16, u16)> {
 
     cursor.seek(SeekFrom::Start(ipv4_header_len as u64)).ok()?;
 
-    if cursor.read_u8().ok()? != ECHO_REPLY_TYPE {
+    match (cursor.read_u8().ok()?, cursor.read_u8().ok()?) {
+        (ECHO_REPLY_TYPE, ECHO_REPLY_CODE) => {
+            let _checksum = cursor.read_u16::<BigEndian>().ok()?;
+            let id = cursor.read_u16::<BigEndian>().ok()?;
+            let seq = cursor.read_u16::<BigEndian>().ok()?;
+
+            if packet.len() != cursor.position() as usize {
+                return None;
+            }
+
+            Some(IcmpReply::Echo { id, seq })
+        }
+        (TIME_EXCEEDED_TYPE, TTL_EXCEEDED_CODE) => {
+            let (id, seq, dst) = parse_original_v4(&mut cursor)?;
+            Some(IcmpReply::TimeExceeded { id, seq, dst })
+        }
+        (DESTINATION_UNREACHABLE_TYPE, code) => {
+            let (id, seq, dst) = parse_original_v4(&mut cursor)?;
+            Some(IcmpReply::Unreachable { id, seq, dst, code })
+        }
+        _ => None,
+    }
+}
+
+/// Parses the original IPv4 header and echo request embedded in an ICMP error message,
+/// returning the id, sequence number and destination of the request
+fn parse_original_v4(cursor: &mut Cursor<&[u8]>) -> Option<(u16, u16, Ipv4Addr)> {
+    const ECHO_REQUEST_TYPE: u8 = 8;
+    const ICMP_PROTOCOL: u8 = 1;
+
+    let _checksum = cursor.read_u16::<BigEndian>().ok()?;
+    let _unused = cursor.read_u32::<BigEndian>().ok()?;
+
+    let original = cursor.position();
+    let original_header_len = (cursor.read_u8().ok()? & 0xF) * 4;
+    // Other protocols, such as UDP, may start with the echo request type too
+    cursor.seek(SeekFrom::Start(original + 9)).ok()?;
+    if cursor.read_u8().ok()? != ICMP_PROTOCOL {
         return None;
     }
+    cursor.seek(SeekFrom::Start(original + 16)).ok()?;
+    let dst = Ipv4Addr::from(cursor.read_u32::<BigEndian>().ok()?);
+    cursor
+        .seek(SeekFrom::Start(original + original_header_len as u64))
+        .ok()?;
 
-    if cursor.read_u8().ok()? != ECHO_REPLY_CODE {
+    if cursor.read_u8().ok()? != ECHO_REQUEST_TYPE {
         return None;
     }
 
+    let _code = cursor.read_u8().ok()?;
     let _checksum = cursor.read_u16::<BigEndian>().ok()?;
     let id = cursor.read_u16::<BigEndian>().ok()?;
     let seq = cursor.read_u16::<BigEndian>().ok()?;
 
-    if packet.len() != cursor.position() as usize {
-        return None;
-    }
-
-    Some((id, seq))
+    Some((id, seq, dst))
 }
 
 fn checksum(buffer: &mut [u8], sum: &mut u32) {
@@ -281,3 +605,192 @@ pub fn _ping_test() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const TARGET: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+    const US: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    /// An IPv4 header carrying ICMP with `options` words of options
+    fn ipv4_header(src: Ipv4Addr, dst: Ipv4Addr, options: u8) -> Vec<u8> {
+        let mut header = vec![0x45 + options, 0, 0, 0, 0, 0, 0x40, 0, 64, 1, 0, 0];
+        header.extend_from_slice(&src.octets());
+        header.extend_from_slice(&dst.octets());
+        header.extend(vec![1; options as usize * 4]);
+        header
+    }
+
+    /// The echo request we sent to `TARGET`, as quoted in ICMP error messages
+    fn request(id: u16, seq: u16) -> Vec<u8> {
+        let mut packet = ipv4_header(US, TARGET, 0);
+        packet.extend_from_slice(&[8, 0, 0, 0]);
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet
+    }
+
+    /// An ICMP error message from `ROUTER` quoting `original`
+    fn error(kind: u8, code: u8, original: &[u8]) -> Vec<u8> {
+        let mut packet = ipv4_header(ROUTER, US, 0);
+        packet.extend_from_slice(&[kind, code, 0xab, 0xcd, 0, 0, 0, 0]);
+        packet.extend_from_slice(original);
+        packet
+    }
+
+    #[test]
+    fn time_exceeded() {
+        assert_eq!(
+            parse_ping_v4(&error(11, 0, &request(0x1234, 7))),
+            Some(IcmpReply::TimeExceeded {
+                id: 0x1234,
+                seq: 7,
+                dst: TARGET
+            })
+        );
+
+        // Fragment reassembly time exceeded isn't caused by the TTL
+        assert_eq!(parse_ping_v4(&error(11, 1, &request(0x1234, 7))), None);
+    }
+
+    #[test]
+    fn echo_reply() {
+        let mut packet = ipv4_header(TARGET, US, 0);
+        packet.extend_from_slice(&[0, 0, 0xab, 0xcd, 0x12, 0x34, 0, 7]);
+        assert_eq!(
+            parse_ping_v4(&packet),
+            Some(IcmpReply::Echo { id: 0x1234, seq: 7 })
+        );
+
+        // Our requests have no payload, so replies with one aren't ours
+        packet.push(0);
+        assert_eq!(parse_ping_v4(&packet), None);
+    }
+
+    #[test]
+    fn unreachable_codes() {
+        let codes = [
+            Unreachable::Network,
+            Unreachable::Host,
+            Unreachable::Protocol,
+            Unreachable::Port,
+            Unreachable::FragmentationNeeded,
+            Unreachable::SourceRouteFailed,
+            Unreachable::NetworkUnknown,
+            Unreachable::HostUnknown,
+            Unreachable::Other(8),
+            Unreachable::AdminProhibited,
+            Unreachable::AdminProhibited,
+            Unreachable::Other(11),
+            Unreachable::Other(12),
+            Unreachable::AdminProhibited,
+            Unreachable::Other(14),
+            Unreachable::Other(15),
+        ];
+
+        for (code, &expected) in (0..).zip(codes.iter()) {
+            let reply = parse_ping_v4(&error(3, code, &request(0x1234, 7)));
+            assert_eq!(
+                reply,
+                Some(IcmpReply::Unreachable {
+                    id: 0x1234,
+                    seq: 7,
+                    dst: TARGET,
+                    code
+                })
+            );
+            assert_eq!(Unreachable::from_code(code), expected);
+        }
+    }
+
+    #[test]
+    fn header_options() {
+        // Both the outer and the quoted header may have options
+        let mut original = ipv4_header(US, TARGET, 2);
+        original.extend_from_slice(&[8, 0, 0, 0, 0x12, 0x34, 0, 7]);
+        let mut packet = ipv4_header(ROUTER, US, 3);
+        packet.extend_from_slice(&[3, 1, 0xab, 0xcd, 0, 0, 0, 0]);
+        packet.extend_from_slice(&original);
+
+        assert_eq!(
+            parse_ping_v4(&packet),
+            Some(IcmpReply::Unreachable {
+                id: 0x1234,
+                seq: 7,
+                dst: TARGET,
+                code: 1
+            })
+        );
+    }
+
+    #[test]
+    fn short_packets() {
+        for packet in [
+            error(3, 1, &request(0x1234, 7)),
+            error(11, 0, &request(0x1234, 7)),
+        ]
+        .iter()
+        {
+            for len in 0..packet.len() {
+                assert_eq!(parse_ping_v4(&packet[..len]), None, "{} bytes", len);
+            }
+        }
+
+        // A header length pointing past the end
+        let mut packet = error(3, 1, &request(0x1234, 7));
+        packet[0] = 0x4f;
+        assert_eq!(parse_ping_v4(&packet), None);
+    }
+
+    #[test]
+    fn foreign_requests() {
+        // Another program's echo request is parsed with its id and ignored by `ping_task`
+        assert_eq!(
+            parse_ping_v4(&error(3, 1, &request(0x4321, 7))),
+            Some(IcmpReply::Unreachable {
+                id: 0x4321,
+                seq: 7,
+                dst: TARGET,
+                code: 1
+            })
+        );
+
+        // Errors about other protocols, such as a UDP traceroute, aren't echo requests
+        let mut udp = ipv4_header(US, TARGET, 0);
+        udp[9] = 17;
+        udp.extend_from_slice(&[0x82, 0x9b, 0x82, 0x9b, 0, 8, 0, 0]);
+        assert_eq!(parse_ping_v4(&error(3, 3, &udp)), None);
+
+        // Even if the source port starts with the echo request type
+        let mut udp = ipv4_header(US, TARGET, 0);
+        udp[9] = 17;
+        udp.extend_from_slice(&[0x08, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        assert_eq!(parse_ping_v4(&error(3, 3, &udp)), None);
+    }
+
+    /// Answers every request with Destination Unreachable quoting a different id
+    struct Foreign(Events);
+
+    impl Transport for Foreign {
+        fn send(&mut self, ip: Ipv4Addr, id: u16, seq: u16, _ttl: Option<u32>) {
+            let reply = IcmpReply::Unreachable {
+                id: id.wrapping_add(1),
+                seq,
+                dst: ip,
+                code: 1,
+            };
+            self.0
+                .send(Event::Reply(ROUTER, reply, Instant::now()))
+                .ok();
+        }
+    }
+
+    #[tokio::test]
+    async fn foreign_id_ignored() {
+        tokio::time::pause();
+        let mut ping = Ping::with_transport(|events| -> io::Result<_> { Ok(Foreign(events)) });
+        assert_eq!(ping.ping(TARGET).await, Err(PingError::Timeout));
+    }
+}
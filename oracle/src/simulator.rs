@@ -0,0 +1,149 @@
+//! A simulated network which answers pings without root or a real network.
+//!
+//! Time is taken from Tokio's clock, so tests pausing it with `tokio::time::pause` run
+//! deterministically and skip ahead whenever all tasks are waiting.
+
+use crate::ping::{Event, Events, IcmpReply, Ping, Transport};
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::spawn;
+use tokio::time::{delay_for, Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct Host {
+    pub latency: Duration,
+    /// Probability of a ping or its reply getting lost
+    pub loss: f64,
+    /// Periods, relative to the creation of the network, when the host doesn't answer
+    pub outages: Vec<Range<Duration>>,
+    /// A router and Destination Unreachable code it reports for the host during outages
+    pub router: Option<(Ipv4Addr, u8)>,
+    /// Routers on the path to the host, which report when the TTL runs out at them
+    pub route: Vec<Ipv4Addr>,
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Host {
+            latency: Duration::from_millis(1),
+            loss: 0.0,
+            outages: Vec::new(),
+            router: None,
+            route: Vec::new(),
+        }
+    }
+}
+
+struct Inner {
+    start: Instant,
+    hosts: HashMap<Ipv4Addr, Host>,
+    rng: StdRng,
+    sent: usize,
+}
+
+#[derive(Clone)]
+pub struct Network(Arc<Mutex<Inner>>);
+
+impl Network {
+    /// Creates an empty network, `seed` decides which pings are lost
+    pub fn new(seed: u64) -> Self {
+        Network(Arc::new(Mutex::new(Inner {
+            start: Instant::now(),
+            hosts: HashMap::new(),
+            rng: StdRng::seed_from_u64(seed),
+            sent: 0,
+        })))
+    }
+
+    pub fn host(&self, ip: Ipv4Addr, host: Host) {
+        self.0.lock().hosts.insert(ip, host);
+    }
+
+    /// Adds an outage from `from` until `to` after the creation of the network
+    pub fn outage(&self, ip: Ipv4Addr, from: Duration, to: Duration) {
+        if let Some(host) = self.0.lock().hosts.get_mut(&ip) {
+            host.outages.push(from..to);
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().saturating_duration_since(self.0.lock().start)
+    }
+
+    /// The number of echo requests sent
+    pub fn sent(&self) -> usize {
+        self.0.lock().sent
+    }
+
+    /// Creates a `Ping` which sends its echo requests on this network
+    pub fn ping(&self) -> Ping {
+        let network = self.clone();
+        Ping::with_transport(move |events| -> io::Result<_> { Ok(Simulated { network, events }) })
+    }
+
+    /// Returns the reply to an echo request and when it arrives
+    fn answer(
+        &self,
+        ip: Ipv4Addr,
+        id: u16,
+        seq: u16,
+        ttl: Option<u32>,
+    ) -> Option<(Duration, Ipv4Addr, IcmpReply)> {
+        let mut inner = self.0.lock();
+        inner.sent += 1;
+
+        let elapsed = Instant::now().saturating_duration_since(inner.start);
+        let host = inner.hosts.get(&ip)?.clone();
+
+        if host.loss > 0.0 && inner.rng.gen::<f64>() < host.loss {
+            return None;
+        }
+
+        // Each router on the way adds an equal part of the latency
+        if let Some(ttl) = ttl.filter(|&ttl| ttl > 0 && ttl as usize <= host.route.len()) {
+            let router = host.route[ttl as usize - 1];
+            let latency = host.latency * ttl / (host.route.len() as u32 + 1);
+            return Some((
+                latency,
+                router,
+                IcmpReply::TimeExceeded { id, seq, dst: ip },
+            ));
+        }
+
+        if host.outages.iter().any(|outage| outage.contains(&elapsed)) {
+            return host.router.map(|(router, code)| {
+                let reply = IcmpReply::Unreachable {
+                    id,
+                    seq,
+                    dst: ip,
+                    code,
+                };
+                (host.latency / 2, router, reply)
+            });
+        }
+
+        Some((host.latency, ip, IcmpReply::Echo { id, seq }))
+    }
+}
+
+struct Simulated {
+    network: Network,
+    events: Events,
+}
+
+impl Transport for Simulated {
+    fn send(&mut self, ip: Ipv4Addr, id: u16, seq: u16, ttl: Option<u32>) {
+        if let Some((latency, from, reply)) = self.network.answer(ip, id, seq, ttl) {
+            let events = self.events.clone();
+            spawn(async move {
+                delay_for(latency).await;
+                events.send(Event::Reply(from, reply, Instant::now())).ok();
+            });
+        }
+    }
+}
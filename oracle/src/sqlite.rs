@@ -0,0 +1,554 @@
+//! A SQLite database holding devices, configuration, status transitions, latency samples
+//! and log entries
+
+use crate::devices::{DeviceConf, DeviceId, Quality, Round};
+use crate::history::Transition;
+use crate::log::{Entry, Kind, Log};
+use crate::state::Configuration;
+use crate::storage::{Json, Storage};
+use parking_lot::Mutex;
+use rusqlite::{params, types::Type, Connection, OptionalExtension};
+use serde_json::Value;
+use std::io;
+use std::iter;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The initial schema. Devices and the configuration are stored as JSON.
+const SCHEMA: &str = "CREATE TABLE devices (
+        id INTEGER PRIMARY KEY,
+        conf TEXT NOT NULL
+    );
+    CREATE TABLE config (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        conf TEXT NOT NULL
+    );
+    CREATE TABLE transitions (
+        id INTEGER PRIMARY KEY,
+        device INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        time REAL NOT NULL,
+        maintenance INTEGER NOT NULL,
+        rtt REAL,
+        loss REAL
+    );
+    CREATE INDEX transitions_device ON transitions (device, time);
+    CREATE TABLE rounds (
+        device INTEGER NOT NULL,
+        time REAL NOT NULL,
+        sent INTEGER NOT NULL,
+        received INTEGER NOT NULL,
+        loss REAL NOT NULL,
+        min REAL,
+        avg REAL,
+        max REAL,
+        jitter REAL
+    );
+    CREATE INDEX rounds_device ON rounds (device, time);
+    CREATE TABLE log (
+        id INTEGER PRIMARY KEY,
+        kind TEXT NOT NULL,
+        msg TEXT NOT NULL,
+        time REAL NOT NULL
+    );";
+
+/// Schema changes, applied in order. The number applied is kept in `user_version`.
+const MIGRATIONS: &[&str] = &[SCHEMA];
+
+/// Status transitions, latency samples and log entries older than this are deleted
+const RETENTION: Duration = Duration::from_secs(90 * 86400);
+
+/// How often history older than `RETENTION` is deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How long to wait for the other connection to finish writing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs_f64())
+        .unwrap_or_default()
+}
+
+fn time(seconds: f64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs_f64(seconds.max(0.0))
+}
+
+fn io_error(error: rusqlite::Error) -> io::Error {
+    io::Error::other(error)
+}
+
+/// History for the writer thread
+enum Record {
+    Transition(Transition),
+    Round(DeviceId, Round),
+    Log(Entry),
+    /// Deletes the history of a removed device
+    Remove(DeviceId),
+}
+
+pub struct Sqlite {
+    connection: Mutex<Connection>,
+    /// Sends history to the writer thread, so inserts don't block the executor
+    records: Option<mpsc::Sender<Record>>,
+    writer: Option<JoinHandle<()>>,
+    log: Arc<Log>,
+}
+
+impl Sqlite {
+    /// Opens the database, importing the devices and configuration from `json` if it's
+    /// created
+    pub fn open(path: &Path, json: Json, log: Arc<Log>) -> Result<Self, String> {
+        let mut connection = Connection::open(path).map_err(|error| error.to_string())?;
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .map_err(|error| error.to_string())?;
+        let mut imported = None;
+        migrate(&mut connection, |connection| {
+            imported = import(connection, &json)?;
+            Ok(())
+        })?;
+        if let Some(devices) = imported {
+            log.note(&format!("Imported {} devices into the database", devices));
+        }
+        // Before the history is loaded, after that the writer deletes old history
+        if let Err(error) = prune(&connection) {
+            log.log(
+                Kind::Error,
+                &format!("Unable to delete old history: {}", error),
+            );
+        }
+
+        let writer_connection = Connection::open(path).map_err(|error| error.to_string())?;
+        writer_connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .map_err(|error| error.to_string())?;
+        let (records, receiver) = mpsc::channel();
+        let writer = thread::spawn(move || writer(writer_connection, receiver));
+
+        Ok(Sqlite {
+            connection: Mutex::new(connection),
+            records: Some(records),
+            writer: Some(writer),
+            log,
+        })
+    }
+
+    fn send(&self, record: Record) {
+        if let Some(records) = &self.records {
+            records.send(record).ok();
+        }
+    }
+
+    fn loaded<T>(&self, what: &str, result: rusqlite::Result<Vec<T>>) -> Vec<T> {
+        result.unwrap_or_else(|error| {
+            self.log.log(
+                Kind::Error,
+                &format!("Unable to load the {} from the database: {}", what, error),
+            );
+            Vec::new()
+        })
+    }
+}
+
+impl Drop for Sqlite {
+    /// Waits for the history sent so far to be written
+    fn drop(&mut self) {
+        self.records.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+/// Applies the migrations which haven't been yet. A new database is filled by `created`
+/// in the transaction creating it, so it's never left half imported.
+fn migrate(
+    connection: &mut Connection,
+    created: impl FnOnce(&Connection) -> Result<(), String>,
+) -> Result<(), String> {
+    let error = |error: rusqlite::Error| error.to_string();
+    let version: usize = connection
+        .query_row("PRAGMA user_version", params![], |row| row.get::<_, i64>(0))
+        .map_err(error)? as usize;
+
+    let mut created = Some(created);
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction().map_err(error)?;
+        transaction.execute_batch(migration).map_err(error)?;
+        if i == 0 {
+            (created.take().unwrap())(&transaction)?;
+        }
+        transaction
+            .execute_batch(&format!("PRAGMA user_version = {}", i + 1))
+            .map_err(error)?;
+        transaction.commit().map_err(error)?;
+    }
+
+    Ok(())
+}
+
+/// Imports the JSON files which exist, returns the number of devices imported
+fn import(connection: &Connection, json: &Json) -> Result<Option<usize>, String> {
+    if json.config.exists() {
+        let conf = json.load_config()?;
+        write_config(connection, &conf).map_err(|error| error.to_string())?;
+    }
+    if json.devices.exists() {
+        let devices = json.load_devices()?;
+        write_devices(connection, &devices).map_err(|error| error.to_string())?;
+        return Ok(Some(devices.len()));
+    }
+    Ok(None)
+}
+
+fn write_config(connection: &Connection, conf: &Configuration) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO config (id, conf) VALUES (0, ?)",
+        params![serde_json::to_string(conf).unwrap()],
+    )?;
+    Ok(())
+}
+
+/// Replaces the devices, which should be done in a transaction
+fn write_devices(connection: &Connection, devices: &[DeviceConf]) -> rusqlite::Result<()> {
+    connection.execute("DELETE FROM devices", params![])?;
+    for device in devices {
+        connection.execute(
+            "INSERT INTO devices (id, conf) VALUES (?, ?)",
+            params![device.id, serde_json::to_string(device).unwrap()],
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the history it receives, a batch per transaction, and deletes old history
+/// every `PRUNE_INTERVAL`
+fn writer(mut connection: Connection, records: mpsc::Receiver<Record>) {
+    let mut pruned = Instant::now();
+
+    loop {
+        if pruned.elapsed() >= PRUNE_INTERVAL {
+            // Errors aren't logged, as log entries are written here too
+            if let Err(error) = prune(&connection) {
+                eprintln!("Unable to delete old history: {}", error);
+            }
+            pruned = Instant::now();
+        }
+
+        let record = match records.recv_timeout(PRUNE_INTERVAL) {
+            Ok(record) => record,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        let batch: Vec<_> = iter::once(record).chain(records.try_iter()).collect();
+        if let Err(error) = write(&mut connection, &batch) {
+            eprintln!("Unable to write history to the database: {}", error);
+        }
+    }
+}
+
+fn write(connection: &mut Connection, records: &[Record]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+
+    for record in records {
+        match record {
+            Record::Transition(transition) => {
+                let status = serde_json::to_value(transition.status).unwrap();
+                let quality = transition.quality;
+                transaction.execute(
+                    "INSERT INTO transitions (device, status, time, maintenance, rtt, loss)
+                     VALUES (?, ?, ?, ?, ?, ?)",
+                    params![
+                        transition.device,
+                        status.as_str(),
+                        seconds(transition.time),
+                        transition.maintenance,
+                        quality.and_then(|quality| quality.rtt),
+                        quality.map(|quality| quality.loss),
+                    ],
+                )?;
+            }
+            Record::Round(device, round) => {
+                transaction.execute(
+                    "INSERT INTO rounds (device, time, sent, received, loss, min, avg, max, jitter)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                    params![
+                        device,
+                        seconds(round.time),
+                        round.sent as i64,
+                        round.received as i64,
+                        round.loss,
+                        round.min,
+                        round.avg,
+                        round.max,
+                        round.jitter,
+                    ],
+                )?;
+            }
+            Record::Log(entry) => {
+                let kind = match entry.kind {
+                    Kind::Note => "Note",
+                    Kind::Error => "Error",
+                };
+                transaction.execute(
+                    "INSERT INTO log (kind, msg, time) VALUES (?, ?, ?)",
+                    params![kind, entry.msg, seconds(entry.time)],
+                )?;
+            }
+            Record::Remove(device) => {
+                transaction.execute("DELETE FROM transitions WHERE device = ?", params![device])?;
+                transaction.execute("DELETE FROM rounds WHERE device = ?", params![device])?;
+            }
+        }
+    }
+
+    transaction.commit()
+}
+
+/// Deletes history older than `RETENTION`
+fn prune(connection: &Connection) -> rusqlite::Result<()> {
+    let before = SystemTime::now()
+        .checked_sub(RETENTION)
+        .map_or(0.0, seconds);
+    for table in &["transitions", "rounds", "log"] {
+        connection.execute(
+            &format!("DELETE FROM {} WHERE time < ?", table),
+            params![before],
+        )?;
+    }
+    Ok(())
+}
+
+/// Loads the latest `limit` transitions, oldest first
+fn transitions(connection: &Connection, limit: usize) -> rusqlite::Result<Vec<Transition>> {
+    let mut statement = connection.prepare(
+        "SELECT device, status, time, maintenance, rtt, loss
+         FROM (SELECT * FROM transitions ORDER BY id DESC LIMIT ?)
+         ORDER BY id",
+    )?;
+    let transitions = statement.query_map(params![limit as i64], |row| {
+        let status = serde_json::from_value(Value::String(row.get(1)?)).map_err(|error| {
+            rusqlite::Error::FromSqlConversionFailure(1, Type::Text, error.into())
+        })?;
+        let rtt = row.get(4)?;
+        let loss: Option<f64> = row.get(5)?;
+        Ok(Transition {
+            device: row.get(0)?,
+            status,
+            time: time(row.get(2)?),
+            maintenance: row.get(3)?,
+            quality: loss.map(|loss| Quality { rtt, loss }),
+        })
+    })?;
+    transitions.collect()
+}
+
+/// Loads the latest `limit` rounds of each device, oldest first
+fn rounds(connection: &Connection, limit: usize) -> rusqlite::Result<Vec<(DeviceId, Round)>> {
+    let mut statement = connection.prepare(
+        "SELECT device, time, sent, received, loss, min, avg, max, jitter
+         FROM (
+             SELECT *, ROW_NUMBER() OVER (PARTITION BY device ORDER BY time DESC) AS n
+             FROM rounds
+         )
+         WHERE n <= ?
+         ORDER BY time",
+    )?;
+    let rounds = statement.query_map(params![limit as i64], |row| {
+        Ok((
+            row.get(0)?,
+            Round {
+                time: time(row.get(1)?),
+                sent: row.get::<_, i64>(2)? as usize,
+                received: row.get::<_, i64>(3)? as usize,
+                loss: row.get(4)?,
+                min: row.get(5)?,
+                avg: row.get(6)?,
+                max: row.get(7)?,
+                jitter: row.get(8)?,
+            },
+        ))
+    })?;
+    rounds.collect()
+}
+
+impl Storage for Sqlite {
+    fn load_devices(&self) -> Result<Vec<DeviceConf>, String> {
+        let connection = self.connection.lock();
+        let mut statement = connection
+            .prepare("SELECT conf FROM devices ORDER BY id")
+            .map_err(|error| error.to_string())?;
+        let confs = statement
+            .query_map(params![], |row| row.get::<_, String>(0))
+            .map_err(|error| error.to_string())?;
+
+        confs
+            .map(|conf| {
+                let conf = conf.map_err(|error| error.to_string())?;
+                serde_json::from_str(&conf).map_err(|error| error.to_string())
+            })
+            .collect()
+    }
+
+    fn save_devices(&self, devices: &[DeviceConf]) -> io::Result<()> {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction().map_err(io_error)?;
+        write_devices(&transaction, devices).map_err(io_error)?;
+        transaction.commit().map_err(io_error)
+    }
+
+    fn load_config(&self) -> Result<Configuration, String> {
+        let conf: Option<String> = self
+            .connection
+            .lock()
+            .query_row("SELECT conf FROM config WHERE id = 0", params![], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(|error| error.to_string())?;
+
+        let conf = conf.ok_or("The database has no configuration")?;
+        serde_json::from_str(&conf).map_err(|error| error.to_string())
+    }
+
+    fn save_config(&self, conf: &Configuration) -> io::Result<()> {
+        write_config(&self.connection.lock(), conf).map_err(io_error)
+    }
+
+    fn record_transition(&self, transition: &Transition) {
+        self.send(Record::Transition(transition.clone()));
+    }
+
+    fn record_round(&self, device: DeviceId, round: &Round) {
+        self.send(Record::Round(device, *round));
+    }
+
+    fn record_log(&self, entry: &Entry) {
+        self.send(Record::Log(entry.clone()));
+    }
+
+    fn remove_history(&self, device: DeviceId) {
+        self.send(Record::Remove(device));
+    }
+
+    fn load_transitions(&self, limit: usize) -> Vec<Transition> {
+        let result = transitions(&self.connection.lock(), limit);
+        self.loaded("status transitions", result)
+    }
+
+    fn load_rounds(&self, limit: usize) -> Vec<(DeviceId, Round)> {
+        let result = rounds(&self.connection.lock(), limit);
+        self.loaded("latency samples", result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::ServiceStatus;
+    use crate::tests::{temp_dir, CONFIG};
+    use std::fs;
+
+    fn open(dir: &Path) -> Sqlite {
+        let log = Arc::new(Log::new());
+        let json = Json::new(dir, dir.join("config.json"), log.clone());
+        Sqlite::open(&dir.join("oracle.db"), json, log).unwrap()
+    }
+
+    fn transition(device: DeviceId, time: SystemTime) -> Transition {
+        Transition {
+            device,
+            status: ServiceStatus::Degraded,
+            time,
+            maintenance: false,
+            quality: Some(Quality {
+                rtt: Some(120.0),
+                loss: 0.0,
+            }),
+        }
+    }
+
+    #[test]
+    fn history_is_reloaded() {
+        let dir = temp_dir("sqlite-history");
+        // Whole seconds, which are stored exactly
+        let now = time(seconds(SystemTime::now()).floor());
+        let round = |secs| Round::new(now + Duration::from_secs(secs), &[None]);
+
+        let sqlite = open(&dir);
+        sqlite.record_transition(&transition(1, now - RETENTION * 2));
+        sqlite.record_transition(&transition(1, now));
+        sqlite.record_transition(&transition(2, now));
+        for secs in 0..3 {
+            sqlite.record_round(1, &round(secs));
+        }
+        sqlite.record_round(2, &round(0));
+        sqlite.remove_history(2);
+        drop(sqlite);
+
+        // Old history is deleted when the database is opened
+        let sqlite = open(&dir);
+        let transitions = sqlite.load_transitions(10);
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].device, 1);
+        assert_eq!(transitions[0].time, now);
+        assert_eq!(transitions[0].status, ServiceStatus::Degraded);
+        assert_eq!(transitions[0].quality.unwrap().rtt, Some(120.0));
+        assert_eq!(sqlite.load_rounds(2), vec![(1, round(1)), (1, round(2))]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn version(dir: &Path) -> i64 {
+        Connection::open(dir.join("oracle.db"))
+            .unwrap()
+            .query_row("PRAGMA user_version", params![], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn migrate_imports_once() {
+        let dir = temp_dir("sqlite-migrate");
+        fs::write(dir.join("config.json"), CONFIG).unwrap();
+        fs::write(dir.join("devices.json"), r#"[{"id": 3, "name": "router"}]"#).unwrap();
+
+        let sqlite = open(&dir);
+        assert_eq!(version(&dir), MIGRATIONS.len() as i64);
+        assert_eq!(sqlite.load_config().unwrap().config.web_port, 0);
+        let devices = sqlite.load_devices().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, 3);
+        assert_eq!(devices[0].name.as_deref(), Some("router"));
+        drop(sqlite);
+
+        // The JSON files are only imported into a new database
+        fs::write(dir.join("devices.json"), "[]").unwrap();
+        assert_eq!(open(&dir).load_devices().unwrap().len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_import_is_retried() {
+        let dir = temp_dir("sqlite-import");
+        fs::write(dir.join("config.json"), CONFIG).unwrap();
+        fs::write(dir.join("devices.json"), "[{").unwrap();
+
+        let log = Arc::new(Log::new());
+        let json = Json::new(&dir, dir.join("config.json"), log.clone());
+        assert!(Sqlite::open(&dir.join("oracle.db"), json, log).is_err());
+        assert_eq!(version(&dir), 0);
+
+        fs::write(dir.join("devices.json"), r#"[{"id": 1}]"#).unwrap();
+        let sqlite = open(&dir);
+        assert_eq!(version(&dir), MIGRATIONS.len() as i64);
+        assert!(sqlite.load_config().is_ok());
+        assert_eq!(sqlite.load_devices().unwrap().len(), 1);
+        drop(sqlite);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
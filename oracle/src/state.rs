@@ -1,7 +1,8 @@
+use crate::devices::{DeviceId, ServiceStatus};
+use crate::storage::Storage;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::fs;
+use std::net::Ipv4Addr;
 use std::sync::Arc;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -13,6 +14,147 @@ pub struct Smtp {
     pub recievers: Vec<String>,
 }
 
+/// An external program to run when a device changes status.
+/// Device fields are passed as `ORACLE_*` environment variables and the change as JSON on stdin.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Command {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Devices to run the command for, all devices if empty
+    #[serde(default)]
+    pub devices: Vec<DeviceId>,
+    /// Statuses to run the command on, all statuses if empty
+    #[serde(default)]
+    pub on: Vec<ServiceStatus>,
+    /// Seconds before the command is killed
+    #[serde(default = "default_command_timeout")]
+    pub timeout: u64,
+}
+
+fn default_command_timeout() -> u64 {
+    60
+}
+
+fn default_resolve_interval() -> u64 {
+    300
+}
+
+fn default_mdns_interval() -> u64 {
+    300
+}
+
+fn default_traceroute_interval() -> u64 {
+    3600
+}
+
+fn default_command_concurrency() -> usize {
+    4
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Wol {
+    /// Interface to send wake-on-LAN packets on
+    #[serde(default)]
+    pub interface: Option<String>,
+    #[serde(default = "default_wol_broadcast")]
+    pub broadcast: Ipv4Addr,
+    #[serde(default = "default_wol_port")]
+    pub port: u16,
+    /// Seconds to wait for a device to come up
+    #[serde(default = "default_wol_timeout")]
+    pub timeout: u64,
+}
+
+/// Learning about hosts from the neighbour table and DHCP leases
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Passive {
+    /// Seconds between reading the neighbour table and lease files, disabled if 0
+    #[serde(default = "default_passive_interval")]
+    pub interval: u64,
+    /// dnsmasq or ISC dhcpd lease files
+    #[serde(default = "default_lease_files")]
+    pub lease_files: Vec<String>,
+}
+
+fn default_passive_interval() -> u64 {
+    60
+}
+
+fn default_lease_files() -> Vec<String> {
+    vec![
+        "/var/lib/misc/dnsmasq.leases".to_owned(),
+        "/var/lib/dhcp/dhcpd.leases".to_owned(),
+    ]
+}
+
+impl Default for Passive {
+    fn default() -> Self {
+        Passive {
+            interval: default_passive_interval(),
+            lease_files: default_lease_files(),
+        }
+    }
+}
+
+fn default_wol_broadcast() -> Ipv4Addr {
+    Ipv4Addr::BROADCAST
+}
+
+fn default_wol_port() -> u16 {
+    9
+}
+
+fn default_wol_timeout() -> u64 {
+    300
+}
+
+impl Default for Wol {
+    fn default() -> Self {
+        Wol {
+            interface: None,
+            broadcast: default_wol_broadcast(),
+            port: default_wol_port(),
+            timeout: default_wol_timeout(),
+        }
+    }
+}
+
+/// A service is flapping when it changes status more than `transitions` times within
+/// `window` seconds. It stops flapping once its status has been stable for `window` seconds.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FlapDetection {
+    /// Disabled if 0
+    pub transitions: usize,
+    pub window: u64,
+}
+
+impl Default for FlapDetection {
+    fn default() -> Self {
+        FlapDetection {
+            transitions: 4,
+            window: 600,
+        }
+    }
+}
+
+/// Pings sent in each monitoring round
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Burst {
+    pub count: usize,
+    /// Milliseconds between pings
+    pub interval: u64,
+}
+
+impl Default for Burst {
+    fn default() -> Self {
+        Burst {
+            count: 1,
+            interval: 200,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
     pub web_port: u16,
@@ -31,25 +173,32 @@ pub struct Configuration {
     pub config: Config,
     pub smtp: Option<Smtp>,
     pub users: Vec<User>,
-}
-
-impl Configuration {
-    pub fn load() -> Configuration {
-        serde_json::from_str(&fs::read_to_string("data/config.json").unwrap()).unwrap()
-    }
-
-    pub fn save(&self) {
-        fs::write(
-            "data/config.json",
-            serde_json::to_string_pretty(&self).unwrap(),
-        )
-        .unwrap()
-    }
+    #[serde(default)]
+    pub commands: Vec<Command>,
+    #[serde(default = "default_command_concurrency")]
+    pub command_concurrency: usize,
+    #[serde(default)]
+    pub wol: Wol,
+    #[serde(default)]
+    pub passive: Passive,
+    #[serde(default)]
+    pub flap_detection: FlapDetection,
+    #[serde(default)]
+    pub burst: Burst,
+    /// Seconds between traceroutes for devices with `traceroute` enabled
+    #[serde(default = "default_traceroute_interval")]
+    pub traceroute_interval: u64,
+    /// Seconds between resolving device hostnames
+    #[serde(default = "default_resolve_interval")]
+    pub resolve_interval: u64,
+    /// Seconds between browsing for mDNS services, mDNS discovery is disabled if 0
+    #[serde(default = "default_mdns_interval")]
+    pub mdns_interval: u64,
 }
 
 pub type State = Arc<Mutex<Configuration>>;
 pub type Conf = Arc<Mutex<Configuration>>;
 
-pub fn load() -> State {
-    Arc::new(Mutex::new(Configuration::load()))
+pub fn load(storage: &dyn Storage) -> State {
+    Arc::new(Mutex::new(storage.load_config().unwrap()))
 }
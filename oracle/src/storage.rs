@@ -0,0 +1,114 @@
+//! Where devices, configuration and history are kept. JSON files are used unless the
+//! `sqlite` feature is enabled.
+
+use crate::devices::{DeviceConf, DeviceId, Round};
+use crate::history::Transition;
+use crate::log::{Entry, Log};
+use crate::persist;
+use crate::state::Configuration;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, RecvError};
+
+pub trait Storage: Send + Sync {
+    fn load_devices(&self) -> Result<Vec<DeviceConf>, String>;
+
+    fn save_devices(&self, devices: &[DeviceConf]) -> io::Result<()>;
+
+    fn load_config(&self) -> Result<Configuration, String>;
+
+    fn save_config(&self, conf: &Configuration) -> io::Result<()>;
+
+    /// Status transitions are only kept in memory by default
+    fn record_transition(&self, _transition: &Transition) {}
+
+    /// Latency samples are only kept in memory by default
+    fn record_round(&self, _device: DeviceId, _round: &Round) {}
+
+    fn record_log(&self, _entry: &Entry) {}
+
+    /// Forgets the transitions and latency samples of a removed device
+    fn remove_history(&self, _device: DeviceId) {}
+
+    /// The latest `limit` status transitions, oldest first
+    fn load_transitions(&self, _limit: usize) -> Vec<Transition> {
+        Vec::new()
+    }
+
+    /// The latest `limit` latency samples of each device, oldest first
+    fn load_rounds(&self, _limit: usize) -> Vec<(DeviceId, Round)> {
+        Vec::new()
+    }
+}
+
+/// Stores devices and configuration in JSON files
+pub struct Json {
+    pub devices: PathBuf,
+    pub config: PathBuf,
+    log: Arc<Log>,
+}
+
+impl Json {
+    /// Uses `devices.json` in `data_dir` and the configuration file `config`
+    pub fn new(data_dir: &Path, config: PathBuf, log: Arc<Log>) -> Self {
+        Json {
+            devices: data_dir.join("devices.json"),
+            config,
+            log,
+        }
+    }
+}
+
+impl Storage for Json {
+    fn load_devices(&self) -> Result<Vec<DeviceConf>, String> {
+        persist::load(&self.devices, &self.log)
+    }
+
+    fn save_devices(&self, devices: &[DeviceConf]) -> io::Result<()> {
+        persist::write(
+            &self.devices,
+            &serde_json::to_string_pretty(devices).unwrap(),
+        )
+    }
+
+    fn load_config(&self) -> Result<Configuration, String> {
+        persist::load(&self.config, &self.log)
+    }
+
+    fn save_config(&self, conf: &Configuration) -> io::Result<()> {
+        persist::write(&self.config, &serde_json::to_string_pretty(conf).unwrap())
+    }
+}
+
+/// Opens the database in `data_dir`, which is created from the JSON files
+#[cfg(feature = "sqlite")]
+pub fn open(data_dir: &Path, config: PathBuf, log: Arc<Log>) -> Arc<dyn Storage> {
+    let path = data_dir.join("oracle.db");
+    if path.exists() && config != data_dir.join("config.json") {
+        log.note(&format!(
+            "The configuration is kept in {}, {} is ignored",
+            path.display(),
+            config.display()
+        ));
+    }
+
+    let json = Json::new(data_dir, config, log.clone());
+    Arc::new(crate::sqlite::Sqlite::open(&path, json, log).unwrap())
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn open(data_dir: &Path, config: PathBuf, log: Arc<Log>) -> Arc<dyn Storage> {
+    Arc::new(Json::new(data_dir, config, log))
+}
+
+/// Records log entries as they're logged
+pub async fn log_recorder(mut entries: broadcast::Receiver<Entry>, storage: Arc<dyn Storage>) {
+    loop {
+        match entries.recv().await {
+            Ok(entry) => storage.record_log(&entry),
+            Err(RecvError::Lagged(_)) => (),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
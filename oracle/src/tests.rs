@@ -0,0 +1,856 @@
+//! Monitoring and notification tests on a simulated network
+
+use crate::devices::{self, DeviceChange, DeviceConf, DeviceId, Devices, ServiceStatus};
+use crate::groups::{self, Group, GroupId};
+use crate::hosts;
+use crate::log::Log;
+use crate::maintenance::{self, Maintenance};
+use crate::notifier::{self, Deliver};
+use crate::passive::Neighbour;
+use crate::persist;
+use crate::ping::{PingError, Unreachable};
+use crate::simulator::{Host, Network};
+use crate::state::Configuration;
+use crate::storage::Json;
+use crate::traceroute::{self, Hop};
+use futures::future::join_all;
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::json;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::spawn;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time::{delay_for, Duration};
+
+pub(crate) const CONFIG: &str = r#"{"web_port": 0, "ping_interval": 0, "smtp": null, "users": []}"#;
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+fn ip(last: u8) -> Ipv4Addr {
+    Ipv4Addr::new(10, 0, 0, last)
+}
+
+/// Returns an empty directory for the files written by a test
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("oracle-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub(crate) fn devices(network: &Network) -> Arc<Devices> {
+    let conf: Configuration = serde_json::from_str(CONFIG).unwrap();
+    let log = Arc::new(Log::new());
+    Devices::new(
+        Arc::new(Mutex::new(conf)),
+        log.clone(),
+        network.ping(),
+        Arc::new(Json::new(Path::new("data"), "data/config.json".into(), log)),
+        "data".into(),
+    )
+}
+
+/// Returns devices which keep their files in `dir`
+pub(crate) fn devices_in(dir: &Path) -> Arc<Devices> {
+    let log = Arc::new(Log::new());
+    Devices::new(
+        Arc::new(Mutex::new(serde_json::from_str(CONFIG).unwrap())),
+        log.clone(),
+        Network::new(0).ping(),
+        Arc::new(Json::new(dir, dir.join("config.json"), log)),
+        dir.to_owned(),
+    )
+}
+
+fn add(devices: &Arc<Devices>, conf: DeviceConf) {
+    devices.add(DeviceConf {
+        name: Some(format!("device {}", conf.id)),
+        ..conf
+    });
+}
+
+fn device(id: DeviceId, ip: Ipv4Addr) -> DeviceConf {
+    DeviceConf {
+        id,
+        ipv4: Some(ip),
+        ..Default::default()
+    }
+}
+
+fn status(devices: &Devices, id: DeviceId) -> Option<ServiceStatus> {
+    devices
+        .device(id)
+        .unwrap()
+        .icmpv4
+        .lock()
+        .status
+        .map(|s| s.0)
+}
+
+/// Returns a receiver for the changes passed on to notifiers
+fn notifications(devices: &Devices) -> mpsc::Receiver<DeviceChange> {
+    let (tx, rx) = mpsc::channel(1000);
+    devices.notifiers.lock().push(tx);
+    rx
+}
+
+fn received(
+    rx: &mut mpsc::Receiver<DeviceChange>,
+) -> Vec<(DeviceId, ServiceStatus, ServiceStatus)> {
+    let mut result = Vec::new();
+    while let Ok(change) = rx.try_recv() {
+        if let DeviceChange::IPv4Status {
+            device,
+            old: Some(old),
+            new: Some(new),
+            ..
+        } = change
+        {
+            result.push((device, old.0, new.0));
+        }
+    }
+    result
+}
+
+/// Starts a notifier which records the batches it delivers, failing the first `failures`
+fn notifier(devices: &Arc<Devices>, failures: usize) -> Arc<Mutex<Vec<Vec<DeviceChange>>>> {
+    let batches = Arc::new(Mutex::new(Vec::new()));
+    let batches_ = batches.clone();
+    let deliver: Deliver = Arc::new(move |changes| {
+        let mut batches = batches_.lock();
+        batches.push(changes);
+        batches.len() > failures
+    });
+
+    let (tx, rx) = mpsc::channel(1000);
+    devices.notifiers.lock().push(tx);
+    spawn(notifier::notifier(
+        devices.clone(),
+        devices.log.clone(),
+        "test".to_owned(),
+        deliver,
+        rx,
+    ));
+
+    batches
+}
+
+#[tokio::test]
+async fn up() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(
+        ip(1),
+        Host {
+            latency: Duration::from_millis(5),
+            ..Default::default()
+        },
+    );
+    let devices = devices(&network);
+    let mut rx = notifications(&devices);
+
+    add(&devices, device(0, ip(1)));
+    delay_for(secs(30)).await;
+
+    assert_eq!(status(&devices, 0), Some(ServiceStatus::Up));
+    let rtt = devices
+        .device(0)
+        .unwrap()
+        .icmpv4
+        .lock()
+        .quality
+        .unwrap()
+        .rtt
+        .unwrap();
+    assert!((rtt - 5.0).abs() < 1.0, "rtt {}", rtt);
+
+    // The initial status isn't a change
+    assert!(received(&mut rx).is_empty());
+}
+
+#[tokio::test]
+async fn down_after_retries() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(ip(1), Default::default());
+    network.outage(ip(1), secs(15), secs(3600));
+    let devices = devices(&network);
+    let mut rx = notifications(&devices);
+
+    add(&devices, device(0, ip(1)));
+
+    // The round at 20 seconds fails, but the device is retried for 10 more pings
+    delay_for(secs(35)).await;
+    assert_eq!(status(&devices, 0), Some(ServiceStatus::Up));
+    assert!(received(&mut rx).is_empty());
+
+    delay_for(secs(15)).await;
+    assert_eq!(status(&devices, 0), Some(ServiceStatus::Down));
+    assert_eq!(
+        received(&mut rx),
+        vec![(0, ServiceStatus::Up, ServiceStatus::Down)]
+    );
+    assert_eq!(
+        devices.device(0).unwrap().icmpv4.lock().reason,
+        Some(PingError::Timeout)
+    );
+}
+
+#[tokio::test]
+async fn retry_recovers() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(ip(1), Default::default());
+    network.outage(ip(1), secs(15), secs(23));
+    let devices = devices(&network);
+    let mut rx = notifications(&devices);
+
+    add(&devices, device(0, ip(1)));
+    delay_for(secs(60)).await;
+
+    assert_eq!(status(&devices, 0), Some(ServiceStatus::Up));
+    assert!(received(&mut rx).is_empty());
+    assert!(
+        devices
+            .device(0)
+            .unwrap()
+            .icmpv4
+            .lock()
+            .quality
+            .unwrap()
+            .loss
+            > 0.0
+    );
+
+    // One ping per round at 0, 10, 20, 30, 40 and 50 seconds, plus the retries
+    assert!(network.sent() > 6);
+}
+
+#[tokio::test]
+async fn recovers() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(ip(1), Default::default());
+    network.outage(ip(1), secs(15), secs(60));
+    let devices = devices(&network);
+    let mut rx = notifications(&devices);
+
+    add(&devices, device(0, ip(1)));
+    delay_for(secs(90)).await;
+
+    assert!(network.elapsed() >= secs(90));
+    assert_eq!(status(&devices, 0), Some(ServiceStatus::Up));
+    assert_eq!(
+        received(&mut rx),
+        vec![
+            (0, ServiceStatus::Up, ServiceStatus::Down),
+            (0, ServiceStatus::Down, ServiceStatus::Up)
+        ]
+    );
+    assert_eq!(devices.device(0).unwrap().icmpv4.lock().reason, None);
+}
+
+#[tokio::test]
+async fn down_after_maintenance() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(ip(1), Default::default());
+    network.outage(ip(1), secs(15), secs(3600));
+    let devices = devices(&network);
+    let mut rx = notifications(&devices);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    devices.maintenance.lock().push(Maintenance {
+        id: 0,
+        description: String::new(),
+        devices: vec![0],
+        groups: Vec::new(),
+        start: now - 60,
+        duration: 3600,
+        repeat: None,
+    });
+
+    add(&devices, device(0, ip(1)));
+    delay_for(secs(60)).await;
+    assert_eq!(status(&devices, 0), Some(ServiceStatus::Down));
+    assert!(received(&mut rx).is_empty());
+
+    // The device is notified as down once maintenance ends
+    devices.maintenance.lock().clear();
+    delay_for(secs(30)).await;
+    assert_eq!(
+        received(&mut rx),
+        vec![(0, ServiceStatus::Up, ServiceStatus::Down)]
+    );
+}
+
+#[tokio::test]
+async fn unreachable_reason() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(
+        ip(1),
+        Host {
+            router: Some((ip(254), 1)),
+            ..Default::default()
+        },
+    );
+    network.outage(ip(1), secs(15), secs(3600));
+    let devices = devices(&network);
+    let mut rx = notifications(&devices);
+
+    add(&devices, device(0, ip(1)));
+    delay_for(secs(60)).await;
+
+    assert_eq!(status(&devices, 0), Some(ServiceStatus::Down));
+    assert_eq!(
+        received(&mut rx),
+        vec![(0, ServiceStatus::Up, ServiceStatus::Down)]
+    );
+    assert_eq!(
+        devices.device(0).unwrap().icmpv4.lock().reason,
+        Some(PingError::Unreachable {
+            code: Unreachable::Host,
+            router: ip(254),
+        })
+    );
+}
+
+#[tokio::test]
+async fn parent_down() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(ip(1), Default::default());
+    network.host(ip(2), Default::default());
+    network.outage(ip(1), secs(15), secs(3600));
+    network.outage(ip(2), secs(15), secs(3600));
+    let devices = devices(&network);
+    let mut rx = notifications(&devices);
+
+    add(&devices, device(0, ip(1)));
+    add(
+        &devices,
+        DeviceConf {
+            parents: vec![0],
+            ..device(1, ip(2))
+        },
+    );
+    delay_for(secs(60)).await;
+
+    assert_eq!(status(&devices, 0), Some(ServiceStatus::Down));
+    assert_eq!(status(&devices, 1), Some(ServiceStatus::Unreachable));
+
+    // Only the root cause is notified
+    assert_eq!(
+        received(&mut rx),
+        vec![(0, ServiceStatus::Up, ServiceStatus::Down)]
+    );
+}
+
+#[tokio::test]
+async fn traceroute_hops() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(
+        ip(1),
+        Host {
+            latency: Duration::from_millis(30),
+            router: Some((ip(253), 1)),
+            route: vec![ip(254), ip(253)],
+            ..Default::default()
+        },
+    );
+    network.outage(ip(1), secs(60), secs(3600));
+
+    // Returns the address, unreachable code and round-trip time of each hop. The time is
+    // rounded down to 5 ms as timers add up to a millisecond.
+    let hops = |hops: Vec<Hop>| -> Vec<_> {
+        hops.into_iter()
+            .enumerate()
+            .map(|(i, hop)| {
+                assert_eq!(hop.ttl as usize, i + 1);
+                (
+                    hop.address,
+                    hop.unreachable,
+                    (hop.rtt.unwrap() / 5.0) as u32 * 5,
+                )
+            })
+            .collect()
+    };
+
+    let trace = traceroute::traceroute(&network.ping(), ip(1)).await;
+    assert!(trace.complete);
+    assert_eq!(
+        hops(trace.hops),
+        vec![
+            (Some(ip(254)), None, 10),
+            (Some(ip(253)), None, 20),
+            (Some(ip(1)), None, 30),
+        ]
+    );
+
+    // The last router reports the host as unreachable during the outage
+    delay_for(secs(60)).await;
+    let trace = traceroute::traceroute(&network.ping(), ip(1)).await;
+    assert!(!trace.complete);
+    assert_eq!(
+        hops(trace.hops),
+        vec![
+            (Some(ip(254)), None, 10),
+            (Some(ip(253)), None, 20),
+            (Some(ip(253)), Some(Unreachable::Host), 15),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn degraded_by_loss() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(
+        ip(1),
+        Host {
+            loss: 0.3,
+            ..Default::default()
+        },
+    );
+    let devices = devices(&network);
+
+    add(
+        &devices,
+        DeviceConf {
+            degraded: serde_json::from_str(r#"{"loss": 10}"#).unwrap(),
+            ..device(0, ip(1))
+        },
+    );
+    delay_for(secs(300)).await;
+
+    let history = devices.history.device(0);
+    assert!(history
+        .iter()
+        .any(|transition| transition.status == ServiceStatus::Degraded));
+    assert!(history
+        .iter()
+        .all(|transition| transition.status != ServiceStatus::Down));
+}
+
+#[tokio::test]
+async fn notifier_batches() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(ip(1), Default::default());
+    network.host(ip(2), Default::default());
+    network.outage(ip(1), secs(15), secs(3600));
+    network.outage(ip(2), secs(25), secs(3600));
+    let devices = devices(&network);
+    let batches = notifier(&devices, 0);
+
+    add(&devices, device(0, ip(1)));
+    add(&devices, device(1, ip(2)));
+    delay_for(secs(120)).await;
+
+    // Both devices went down within the 30 seconds the first change is held back
+    let batches = batches.lock();
+    assert_eq!(batches.len(), 1);
+    assert_eq!(batches[0].len(), 2);
+}
+
+#[tokio::test]
+async fn notifier_retries() {
+    tokio::time::pause();
+    let network = Network::new(0);
+    network.host(ip(1), Default::default());
+    network.outage(ip(1), secs(15), secs(100));
+    let devices = devices(&network);
+    let batches = notifier(&devices, 1);
+
+    add(&devices, device(0, ip(1)));
+
+    delay_for(secs(120)).await;
+    assert_eq!(batches.lock().len(), 1);
+
+    // The failed batch is delivered again 5 minutes later along with the recovery
+    delay_for(secs(300)).await;
+    let batches = batches.lock();
+    assert_eq!(batches.len(), 2);
+    assert_eq!(batches[0].len(), 1);
+    assert_eq!(batches[1].len(), 2);
+}
+
+/// Adds, edits and removes thousands of devices from several threads while their monitors
+/// report, then checks the registry is consistent
+#[tokio::test]
+async fn registry_stress() {
+    const WORKERS: u32 = 4;
+    const DEVICES: u32 = 1000;
+    const ROUNDS: u32 = 5000;
+
+    tokio::time::pause();
+    let network = Network::new(0);
+    let address = |i: u32| Ipv4Addr::new(10, 1, (i >> 8) as u8, i as u8);
+    for i in 0..WORKERS * DEVICES {
+        network.host(address(i), Default::default());
+        if i % 7 == 0 {
+            network.outage(address(i), secs(15), secs(3600));
+        }
+    }
+    let devices = devices(&network);
+
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|worker| {
+            let devices = devices.clone();
+            task::spawn_blocking(move || {
+                let mut rng = StdRng::seed_from_u64(worker as u64);
+                for id in worker * DEVICES..(worker + 1) * DEVICES {
+                    add(&devices, device(id, address(id)));
+                }
+
+                // Devices which may have been removed, to check none of them is still monitored
+                let mut seen = Vec::new();
+
+                // Every worker edits and removes devices added by the others too
+                for _ in 0..ROUNDS {
+                    let id = rng.gen_range(0, WORKERS * DEVICES);
+                    match rng.gen_range(0, 4) {
+                        0 => {
+                            seen.extend(devices.device(id));
+                            devices.remove(id);
+                        }
+                        1 => {
+                            add(&devices, device(id, address(id)));
+                            seen.extend(devices.device(id));
+                        }
+                        _ => {
+                            if let Some(device) = devices.device(id) {
+                                let conf = device.conf.lock().clone();
+                                devices.change(
+                                    id,
+                                    DeviceConf {
+                                        ipv4: Some(address(rng.gen_range(0, WORKERS * DEVICES))),
+                                        tags: vec![format!("round {}", worker)],
+                                        ..conf
+                                    },
+                                );
+                            }
+                        }
+                    }
+                    devices.desc(id);
+                    devices.find(|conf| conf.id == id);
+                }
+                seen
+            })
+        })
+        .collect();
+
+    let reader = {
+        let devices = devices.clone();
+        task::spawn_blocking(move || {
+            for _ in 0..100 {
+                let confs = devices.confs();
+                assert!(confs.windows(2).all(|pair| pair[0].id < pair[1].id));
+                devices.unreachable_children(0);
+            }
+        })
+    };
+
+    let mut seen = Vec::new();
+    for worker in join_all(workers).await {
+        seen.extend(worker.unwrap());
+    }
+    reader.await.unwrap();
+
+    // Let the monitors of the remaining devices report
+    delay_for(secs(60)).await;
+
+    let all = devices.all();
+    assert!(!all.is_empty());
+    for device in all {
+        let conf = device.conf.lock().clone();
+        assert!(Arc::ptr_eq(&devices.device(conf.id).unwrap(), &device));
+        assert!(conf.name.is_some());
+        let icmpv4 = device.icmpv4.lock();
+        assert!(icmpv4.monitor.is_some());
+        assert!(icmpv4.status.is_some(), "{} has no status", conf.desc());
+    }
+
+    let removed: Vec<_> = seen
+        .into_iter()
+        .filter(|device| {
+            let id = device.conf.lock().id;
+            !matches!(devices.device(id), Some(registered) if Arc::ptr_eq(&registered, device))
+        })
+        .collect();
+    assert!(!removed.is_empty());
+    for device in removed {
+        assert!(device.icmpv4.lock().monitor.is_none());
+    }
+}
+
+#[test]
+fn persist_recovers_from_backup() {
+    let dir = temp_dir("persist");
+    let path = dir.join("values.json");
+    let log = Log::new();
+
+    for value in 1..=3 {
+        persist::write(&path, &value.to_string()).unwrap();
+    }
+    assert_eq!(persist::load::<u32>(&path, &log), Ok(3));
+
+    // The newest backup is damaged too, so the one before it is used
+    fs::write(&path, "{").unwrap();
+    fs::write(dir.join("values.json.1"), "").unwrap();
+    assert_eq!(persist::load::<u32>(&path, &log), Ok(1));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "1");
+    assert_eq!(
+        fs::read_to_string(dir.join("values.json.corrupt")).unwrap(),
+        "{"
+    );
+
+    fs::remove_file(dir.join("values.json.2")).unwrap();
+    fs::remove_file(&path).unwrap();
+    fs::write(dir.join("values.json.1"), "{").unwrap();
+    assert!(persist::load::<u32>(&path, &log).is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn device_ids_run_out() {
+    let dir = temp_dir("ids");
+    let devices = devices_in(&dir);
+
+    devices.ids.lock().next_device = DeviceId::MAX - 1;
+    assert_eq!(devices.new_device_id().unwrap(), DeviceId::MAX - 1);
+    assert!(devices.new_device_id().is_err());
+    assert_eq!(
+        devices::load_ids(&dir, &Log::new()).unwrap().next_device,
+        DeviceId::MAX
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn corrupt_ids_not_reset() {
+    let dir = temp_dir("corrupt-ids");
+    let log = Log::new();
+    assert_eq!(devices::load_ids(&dir, &log).unwrap().next_device, 0);
+
+    for next_device in 1..=2 {
+        let ids = devices::Ids {
+            next_device,
+            ..Default::default()
+        };
+        devices::save_ids(&dir, &ids).unwrap();
+    }
+    fs::write(dir.join("ids.json"), "{").unwrap();
+    assert_eq!(devices::load_ids(&dir, &log).unwrap().next_device, 1);
+
+    fs::remove_file(dir.join("ids.json.1")).unwrap();
+    fs::write(dir.join("ids.json"), "{").unwrap();
+    assert!(devices::load_ids(&dir, &log).is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn host_inventory() {
+    let dir = temp_dir("hosts");
+    let devices = devices_in(&dir);
+    let mut rx = notifications(&devices);
+    let neighbour = |last, mac: &str| Neighbour {
+        ipv4: ip(last),
+        mac: mac.parse().unwrap(),
+        hostname: None,
+    };
+
+    // An empty scan doesn't end learning mode
+    hosts::observe(&devices, &[]).await;
+    assert!(devices.discovery.hosts.lock().is_none());
+
+    hosts::observe(&devices, &[neighbour(1, "02:00:00:00:00:01")]).await;
+    assert_eq!(hosts::load(&dir, &Log::new()).unwrap().unwrap().len(), 1);
+    assert!(rx.try_recv().is_err());
+
+    // A host answering on several addresses stays where it is
+    for _ in 0..3 {
+        hosts::observe(
+            &devices,
+            &[
+                neighbour(2, "02:00:00:00:00:01"),
+                neighbour(1, "02:00:00:00:00:01"),
+            ],
+        )
+        .await;
+    }
+    assert!(rx.try_recv().is_err());
+
+    hosts::observe(
+        &devices,
+        &[
+            neighbour(2, "02:00:00:00:00:01"),
+            neighbour(3, "02:00:00:00:00:02"),
+        ],
+    )
+    .await;
+    let changes: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+    assert_eq!(changes.len(), 2);
+    assert!(matches!(changes[0], DeviceChange::HostMoved { new, .. } if new == ip(2)));
+    assert!(matches!(changes[1], DeviceChange::NewHost { ipv4, .. } if ipv4 == ip(3)));
+    assert_eq!(hosts::load(&dir, &Log::new()).unwrap().unwrap().len(), 2);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn rejected_groups_use_no_id() {
+    let dir = temp_dir("groups");
+    let devices = devices_in(&dir);
+    let filter = groups::webserver(devices.clone());
+    let add = |group| {
+        warp::test::request()
+            .method("POST")
+            .path("/group")
+            .json(&group)
+            .reply(&filter)
+    };
+
+    let response = add(json!({"id": 0, "name": "orphan", "parent": 7})).await;
+    assert_eq!(response.body(), "error");
+    let response = add(json!({"id": 0, "name": "office"})).await;
+    assert_eq!(response.body(), "");
+    let response = add(json!({"id": 0, "name": "desks", "parent": 0})).await;
+    assert_eq!(response.body(), "");
+
+    let groups = devices.groups.lock().clone();
+    assert_eq!(groups.len(), 2);
+    assert_eq!((groups[0].id, groups[0].parent), (0, None));
+    assert_eq!((groups[1].id, groups[1].parent), (1, Some(0)));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn data_files_recover_from_backup() {
+    let dir = temp_dir("data-files");
+    let log = Log::new();
+    let devices = devices_in(&dir);
+    assert!(groups::load(&dir, &log).unwrap().is_empty());
+    assert!(maintenance::load(&dir, &log).unwrap().is_empty());
+    assert!(hosts::load(&dir, &log).unwrap().is_none());
+
+    for name in &["office", "lab"] {
+        let id = devices.groups.lock().len() as GroupId;
+        devices.groups.lock().push(Group {
+            id,
+            name: name.to_string(),
+            parent: None,
+        });
+        devices.save_groups().unwrap();
+    }
+    fs::write(dir.join("groups.json"), "[{").unwrap();
+    let groups = groups::load(&dir, &log).unwrap();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].name, "office");
+
+    fs::write(dir.join("maintenance.json"), "[{").unwrap();
+    assert!(maintenance::load(&dir, &log).is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn parent_validation() {
+    let conf = |id, parents: &[DeviceId]| DeviceConf {
+        id,
+        parents: parents.to_vec(),
+        ..Default::default()
+    };
+    // 1 -> 0, and 3 <-> 4 already form a cycle
+    let confs = [conf(0, &[]), conf(1, &[0]), conf(3, &[4]), conf(4, &[3])];
+
+    assert_eq!(devices::parents_error(Some(2), &[1], &confs), None);
+    assert_eq!(devices::parents_error(None, &[0, 1], &confs), None);
+    assert_eq!(devices::parents_error(Some(2), &[3], &confs), None);
+    assert_eq!(
+        devices::parents_error(None, &[5], &confs),
+        Some("Unknown parent device 5".to_owned())
+    );
+    assert_eq!(
+        devices::parents_error(Some(1), &[1], &confs),
+        Some("Device 1 can't be its own parent".to_owned())
+    );
+    assert_eq!(
+        devices::parents_error(Some(0), &[1], &confs),
+        Some("The parents of device 0 lead back to it".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn device_with_unknown_parent() {
+    let dir = temp_dir("parents");
+    let devices = devices_in(&dir);
+    let filter = devices::webserver(devices.clone());
+    let add = |device| {
+        warp::test::request()
+            .method("POST")
+            .path("/device")
+            .json(&device)
+            .reply(&filter)
+    };
+
+    let response = add(json!({"id": 0, "ipv4": "10.0.0.1", "parents": [0]})).await;
+    assert_eq!(response.body(), "error");
+    let response = add(json!({"id": 0, "ipv4": "10.0.0.1"})).await;
+    assert_eq!(response.body(), "");
+    let response = add(json!({"id": 0, "ipv4": "10.0.0.2", "parents": [0]})).await;
+    assert_eq!(response.body(), "");
+    assert_eq!(devices.confs()[1].parents, vec![0]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn maintenance_ids_not_reused() {
+    let dir = temp_dir("maintenance");
+    let devices = devices_in(&dir);
+    let filter = maintenance::webserver(devices.clone());
+    let window = json!({"id": 0, "devices": [0], "start": 0, "duration": 60});
+    let add = || {
+        warp::test::request()
+            .method("POST")
+            .path("/maintenance")
+            .json(&window)
+            .reply(&filter)
+    };
+
+    assert_eq!(add().await.body(), "");
+    let response = warp::test::request()
+        .method("DELETE")
+        .path("/maintenance/0")
+        .reply(&filter)
+        .await;
+    assert_eq!(response.body(), "");
+    assert_eq!(add().await.body(), "");
+
+    assert_eq!(devices.maintenance.lock()[0].id, 1);
+    assert_eq!(
+        devices::load_ids(&dir, &Log::new())
+            .unwrap()
+            .next_maintenance,
+        2
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
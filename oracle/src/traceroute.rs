@@ -0,0 +1,169 @@
+use crate::devices::{Device, Devices};
+use crate::ping::{self, Ping, Unreachable};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::time::Duration;
+use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
+
+const MAX_HOPS: u32 = 30;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Hop {
+    pub ttl: u32,
+    /// The router or target which answered, unknown if no answer was received
+    pub address: Option<Ipv4Addr>,
+    /// Round-trip time in milliseconds
+    pub rtt: Option<f64>,
+    /// The router reported that it can't forward to the target
+    #[serde(default)]
+    pub unreachable: Option<Unreachable>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Trace {
+    pub time: SystemTime,
+    pub hops: Vec<Hop>,
+    /// The target answered
+    pub complete: bool,
+}
+
+impl Trace {
+    /// Compares the routers of two traces, ignoring hops which didn't answer in either
+    pub fn changed(&self, other: &Trace) -> bool {
+        self.complete != other.complete
+            || self.hops.len() != other.hops.len()
+            || self
+                .hops
+                .iter()
+                .zip(other.hops.iter())
+                .any(|(a, b)| match (a.address, b.address) {
+                    (Some(a), Some(b)) => a != b,
+                    _ => false,
+                })
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for hop in &self.hops {
+            match (hop.address, hop.rtt) {
+                (Some(address), Some(rtt)) => {
+                    write!(f, "{:>3}. {} ({:.1} ms)", hop.ttl, address, rtt)?
+                }
+                (Some(address), None) => write!(f, "{:>3}. {}", hop.ttl, address)?,
+                _ => write!(f, "{:>3}. *", hop.ttl)?,
+            }
+            match hop.unreachable {
+                Some(code) => writeln!(f, " {}", code)?,
+                None => writeln!(f)?,
+            }
+        }
+        if !self.complete {
+            write!(f, "     Target did not answer")?;
+        }
+        Ok(())
+    }
+}
+
+pub async fn traceroute(ping: &Ping, ip: Ipv4Addr) -> Trace {
+    let time = SystemTime::now();
+
+    // Probe all hops at once
+    let probes = (1..=MAX_HOPS).map(|ttl| {
+        let mut ping = ping.clone();
+        async move { ping.probe(ip, ttl, Duration::from_secs(2)).await.ok() }
+    });
+    let results = join_all(probes).await;
+
+    let mut hops = Vec::new();
+    let mut complete = false;
+
+    for (ttl, result) in (1..).zip(results) {
+        let (address, rtt, unreachable) = match result {
+            Some((ping::Reply::Echo, rtt)) => {
+                complete = true;
+                (Some(ip), Some(rtt), None)
+            }
+            Some((ping::Reply::TimeExceeded(router), rtt)) => (Some(router), Some(rtt), None),
+            Some((ping::Reply::Unreachable(code, router), rtt)) => {
+                (Some(router), Some(rtt), Some(code))
+            }
+            None => (None, None, None),
+        };
+
+        hops.push(Hop {
+            ttl,
+            address,
+            rtt: rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            unreachable,
+        });
+
+        // The path ends at the target or where it can't be forwarded further
+        if complete || unreachable.is_some() {
+            break;
+        }
+    }
+
+    // Only keep hops up to where the path breaks
+    while hops.last().map(|hop| hop.address.is_none()) == Some(true) {
+        hops.pop();
+    }
+
+    Trace {
+        time,
+        hops,
+        complete,
+    }
+}
+
+/// Runs a traceroute to a device and logs if the path changed since the last one
+pub async fn trace_device(devices: Arc<Devices>, device: Arc<Device>) -> Option<Trace> {
+    let (ip, desc) = {
+        let conf = device.conf.lock();
+        (conf.ipv4?, conf.desc())
+    };
+
+    let trace = traceroute(&devices.ping, ip).await;
+
+    let old = device.icmpv4.lock().trace.replace(trace.clone());
+
+    if let Some(old) = old {
+        if old.changed(&trace) {
+            devices
+                .log
+                .note(&format!("Path to device {} changed\n{}", desc, trace));
+        }
+    }
+
+    Some(trace)
+}
+
+async fn run(devices: Arc<Devices>, id: u32) -> Result<String, Rejection> {
+    let device = devices.device(id).ok_or_else(warp::reject::not_found)?;
+    let trace = trace_device(devices, device).await;
+    Ok(serde_json::to_string(&trace).unwrap())
+}
+
+pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
+    let devices_ = devices.clone();
+    let last = warp::path!("device" / u32 / "trace")
+        .and(warp::get())
+        .map(move |id| {
+            let trace = devices_
+                .device(id)
+                .and_then(|device| device.icmpv4.lock().trace.clone());
+            serde_json::to_string(&trace).unwrap()
+        });
+
+    let devices_ = devices.clone();
+    let trace = warp::path!("device" / u32 / "trace")
+        .and(warp::post())
+        .and(warp::any().map(move || devices_.clone()))
+        .and_then(|id, devices| run(devices, id));
+
+    last.or(trace).boxed()
+}
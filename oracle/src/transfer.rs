@@ -0,0 +1,493 @@
+//! Importing and exporting devices as CSV or JSON
+
+use crate::devices::{self, DeviceConf, DeviceId, Devices};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::str;
+use std::sync::Arc;
+use warp::{filters::BoxedFilter, hyper::body::Bytes, reply, Filter, Reply};
+
+/// The columns of CSV files, other fields are only in JSON files
+const COLUMNS: &[&str] = &[
+    "id",
+    "name",
+    "hostname",
+    "ipv4",
+    "mac",
+    "tags",
+    "group",
+    "parents",
+    "always_on",
+    "traceroute",
+];
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    Csv,
+    Json,
+}
+
+fn default_format() -> Format {
+    Format::Json
+}
+
+#[derive(Debug, Deserialize)]
+struct Options {
+    #[serde(default = "default_format")]
+    format: Format,
+    /// Only validate the rows
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct RowError {
+    /// Starting at 1 for the first device
+    row: usize,
+    error: String,
+}
+
+#[derive(Debug, Serialize, Default)]
+struct ImportResult {
+    added: usize,
+    updated: usize,
+    errors: Vec<RowError>,
+    dry_run: bool,
+}
+
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn to_csv(confs: &[DeviceConf]) -> String {
+    let mut csv = COLUMNS.join(",");
+    csv.push('\n');
+
+    for conf in confs {
+        let list = |items: Vec<String>| items.join(";");
+        let fields = [
+            conf.id.to_string(),
+            conf.name.clone().unwrap_or_default(),
+            conf.hostname.clone().unwrap_or_default(),
+            conf.ipv4.map(|ip| ip.to_string()).unwrap_or_default(),
+            conf.mac.map(|mac| mac.to_string()).unwrap_or_default(),
+            list(conf.tags.clone()),
+            conf.group.map(|id| id.to_string()).unwrap_or_default(),
+            list(conf.parents.iter().map(|id| id.to_string()).collect()),
+            conf.always_on.to_string(),
+            conf.traceroute.to_string(),
+        ];
+        let fields: Vec<_> = fields.iter().map(|field| escape(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+/// Splits CSV into records of fields, allowing quoted fields
+fn parse_csv(csv: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = csv.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted => (),
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err("Unterminated quoted field".to_owned());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records.retain(|record| record.iter().any(|field| !field.trim().is_empty()));
+    Ok(records)
+}
+
+/// Converts a CSV field to the JSON value of the device field
+fn csv_value(column: &str, field: &str) -> Result<Value, String> {
+    let field = field.trim();
+    let invalid = || format!("Invalid {} `{}`", column, field);
+
+    Ok(match column {
+        "tags" | "parents" => {
+            let items = field
+                .split(';')
+                .map(str::trim)
+                .filter(|item| !item.is_empty());
+            if column == "tags" {
+                json!(items.collect::<Vec<_>>())
+            } else {
+                let ids: Result<Vec<DeviceId>, _> = items.map(str::parse).collect();
+                json!(ids.map_err(|_| invalid())?)
+            }
+        }
+        "always_on" | "traceroute" => match field.to_lowercase().as_str() {
+            "" | "false" | "no" | "0" => json!(false),
+            "true" | "yes" | "1" => json!(true),
+            _ => return Err(invalid()),
+        },
+        _ if field.is_empty() => Value::Null,
+        "id" | "group" => json!(field.parse::<u32>().map_err(|_| invalid())?),
+        _ => json!(field),
+    })
+}
+
+/// The fields of a device or why they couldn't be read
+type Row = Result<Map<String, Value>, String>;
+
+/// Parses the rows into field maps
+fn rows(format: Format, body: &str) -> Result<Vec<Row>, String> {
+    match format {
+        Format::Json => {
+            let rows: Vec<Value> = serde_json::from_str(body).map_err(|error| error.to_string())?;
+            Ok(rows
+                .into_iter()
+                .map(|row| match row {
+                    Value::Object(row) => Ok(row),
+                    _ => Err("Expected an object".to_owned()),
+                })
+                .collect())
+        }
+        Format::Csv => {
+            let mut records = parse_csv(body)?.into_iter();
+            let header: Vec<String> = records
+                .next()
+                .ok_or("Missing header")?
+                .iter()
+                .map(|column| column.trim().to_lowercase())
+                .collect();
+            if let Some(column) = header.iter().find(|c| !COLUMNS.contains(&c.as_str())) {
+                return Err(format!("Unknown column `{}`", column));
+            }
+
+            Ok(records
+                .map(|record| {
+                    if record.len() != header.len() {
+                        return Err(format!(
+                            "Expected {} fields but found {}",
+                            header.len(),
+                            record.len()
+                        ));
+                    }
+                    header
+                        .iter()
+                        .zip(record.iter())
+                        .map(|(column, field)| Ok((column.clone(), csv_value(column, field)?)))
+                        .collect()
+                })
+                .collect())
+        }
+    }
+}
+
+/// Validates every row, then adds new devices and updates existing ones with a single save.
+/// Rows with an `id` update that device, other rows add a device.
+fn import(devices: &Arc<Devices>, format: Format, body: &str, dry_run: bool) -> ImportResult {
+    let mut result = ImportResult {
+        dry_run,
+        ..Default::default()
+    };
+
+    let rows = match rows(format, body) {
+        Ok(rows) => rows,
+        Err(error) => {
+            result.errors.push(RowError { row: 0, error });
+            return result;
+        }
+    };
+
+    let existing = devices.confs();
+    let groups: HashSet<_> = devices.groups.lock().iter().map(|g| g.id).collect();
+
+    let mut updates = Vec::new();
+    let mut additions = Vec::new();
+    let mut ids = HashSet::new();
+
+    for (i, row) in rows.into_iter().enumerate() {
+        let mut error = |error: String| result.errors.push(RowError { row: i + 1, error });
+
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                error(e);
+                continue;
+            }
+        };
+
+        let id = match row.get("id") {
+            None | Some(Value::Null) => None,
+            Some(id) => match id.as_u64().and_then(|id| DeviceId::try_from(id).ok()) {
+                Some(id) => Some(id),
+                None => {
+                    error(format!("Invalid id {}", id));
+                    continue;
+                }
+            },
+        };
+
+        // Fields missing from the row keep their current value
+        let base = match id {
+            Some(id) => match existing.iter().find(|conf| conf.id == id) {
+                Some(conf) => conf.clone(),
+                None => {
+                    error(format!("Unknown device id {}", id));
+                    continue;
+                }
+            },
+            None => DeviceConf::default(),
+        };
+        let mut conf = serde_json::to_value(base).unwrap();
+        for (key, value) in row {
+            conf[key] = value;
+        }
+        if id.is_none() {
+            conf["id"] = json!(0);
+        }
+
+        let conf: DeviceConf = match serde_json::from_value(conf) {
+            Ok(conf) => conf,
+            Err(e) => {
+                error(e.to_string());
+                continue;
+            }
+        };
+
+        if conf.ipv4.is_none() && conf.hostname.is_none() {
+            error("Either an IPv4 address or a hostname is required".to_owned());
+            continue;
+        }
+        if let Some(group) = conf.group.filter(|group| !groups.contains(group)) {
+            error(format!("Unknown group {}", group));
+            continue;
+        }
+
+        match id {
+            Some(id) => {
+                if !ids.insert(id) {
+                    error(format!("Device {} appears more than once", id));
+                    continue;
+                }
+                updates.push((i + 1, conf));
+            }
+            None => additions.push((i + 1, conf)),
+        }
+    }
+
+    // Parents are checked against the devices as they'll be after the import, as updated
+    // rows may form a cycle between them
+    let after: Vec<_> = existing
+        .iter()
+        .map(
+            |conf| match updates.iter().find(|(_, update)| update.id == conf.id) {
+                Some((_, update)) => update.clone(),
+                None => conf.clone(),
+            },
+        )
+        .collect();
+    let rows = updates
+        .iter()
+        .map(|(row, conf)| (row, Some(conf.id), conf))
+        .chain(additions.iter().map(|(row, conf)| (row, None, conf)));
+    for (&row, id, conf) in rows {
+        if let Some(error) = devices::parents_error(id, &conf.parents, &after) {
+            result.errors.push(RowError { row, error });
+        }
+    }
+    result.errors.sort_by_key(|error| error.row);
+
+    result.added = additions.len();
+    result.updated = updates.len();
+
+    if dry_run || !result.errors.is_empty() {
+        return result;
+    }
+
+    for (_, conf) in updates {
+        devices.change(conf.id, conf);
+    }
+    for (_, conf) in additions {
+        let id = match devices.new_device_id() {
+            Ok(id) => id,
+            Err(error) => {
+                result.errors.push(RowError {
+                    row: 0,
+                    error: format!("Unable to save the next device id: {}", error),
+                });
+                break;
+            }
+        };
+        devices.add(DeviceConf { id, ..conf });
+    }
+    if let Err(error) = devices.save() {
+        result.errors.push(RowError {
+            row: 0,
+            error: format!("Unable to save the devices: {}", error),
+        });
+    }
+
+    result
+}
+
+pub fn webserver(devices: Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
+    let devices_ = devices.clone();
+    let export = warp::path!("devices" / "export")
+        .and(warp::get())
+        .and(warp::query())
+        .map(move |options: Options| {
+            let confs = devices_.confs();
+
+            let (body, content_type, extension) = match options.format {
+                Format::Json => (
+                    serde_json::to_string_pretty(&confs).unwrap(),
+                    "application/json",
+                    "json",
+                ),
+                Format::Csv => (to_csv(&confs), "text/csv", "csv"),
+            };
+
+            let reply = reply::with_header(body, "Content-Type", content_type);
+            reply::with_header(
+                reply,
+                "Content-Disposition",
+                format!("attachment; filename=\"devices.{}\"", extension),
+            )
+        });
+
+    let devices_ = devices.clone();
+    let import = warp::path!("devices" / "import")
+        .and(warp::post())
+        .and(warp::query())
+        .and(warp::body::bytes())
+        .map(move |options: Options, body: Bytes| {
+            let result = match str::from_utf8(&body) {
+                Ok(body) => import(&devices_, options.format, body, options.dry_run),
+                Err(_) => ImportResult {
+                    errors: vec![RowError {
+                        row: 0,
+                        error: "The file isn't UTF-8".to_owned(),
+                    }],
+                    dry_run: options.dry_run,
+                    ..Default::default()
+                },
+            };
+            serde_json::to_string(&result).unwrap()
+        });
+
+    export.or(import).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Network;
+    use crate::tests;
+
+    #[test]
+    fn csv_round_trip() {
+        let conf = DeviceConf {
+            id: 7,
+            name: Some("Printer, \"upstairs\"\nby the stairs".to_owned()),
+            hostname: Some("printer.lan".to_owned()),
+            tags: vec!["office".to_owned(), "a,b".to_owned()],
+            parents: vec![1, 2],
+            ..Default::default()
+        };
+
+        let csv = to_csv(&[conf]);
+        let records = parse_csv(&csv).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], COLUMNS);
+        assert_eq!(records[1][0], "7");
+        assert_eq!(records[1][1], "Printer, \"upstairs\"\nby the stairs");
+        assert_eq!(records[1][2], "printer.lan");
+        assert_eq!(records[1][5], "office;a,b");
+        assert_eq!(records[1][7], "1;2");
+
+        // CRLF line endings, keeping those inside quoted fields
+        let crlf = "id,name\r\n1,\"a\r\nb\"\r\n2,\"\"\"c\"\"\"\r\n";
+        assert_eq!(
+            parse_csv(crlf).unwrap(),
+            vec![vec!["id", "name"], vec!["1", "a\r\nb"], vec!["2", "\"c\""],]
+        );
+        assert!(parse_csv("id,name\n1,\"a").is_err());
+    }
+
+    #[tokio::test]
+    async fn import_rejects_large_ids() {
+        let devices = tests::devices(&Network::new(0));
+
+        let body = r#"[{"id": 4294967297, "ipv4": "10.0.0.1"}]"#;
+        let result = import(&devices, Format::Json, body, true);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].row, 1);
+        assert_eq!(result.errors[0].error, "Invalid id 4294967297");
+    }
+
+    #[tokio::test]
+    async fn import_rejects_parent_cycles() {
+        let devices = tests::devices(&Network::new(0));
+        for id in 0..2 {
+            devices.add(DeviceConf {
+                id,
+                ipv4: Some("10.0.0.1".parse().unwrap()),
+                ..Default::default()
+            });
+        }
+
+        let body = "id,parents\n0,1\n1,0\n";
+        let result = import(&devices, Format::Csv, body, true);
+        let errors: Vec<_> = result
+            .errors
+            .iter()
+            .map(|e| (e.row, &e.error[..]))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (1, "The parents of device 0 lead back to it"),
+                (2, "The parents of device 1 lead back to it"),
+            ]
+        );
+
+        let body = "id,ipv4,parents\n1,10.0.0.2,1\n,10.0.0.3,2\n,10.0.0.4,1\n";
+        let result = import(&devices, Format::Csv, body, true);
+        let errors: Vec<_> = result
+            .errors
+            .iter()
+            .map(|e| (e.row, &e.error[..]))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (1, "Device 1 can't be its own parent"),
+                (2, "Unknown parent device 2"),
+            ]
+        );
+    }
+}
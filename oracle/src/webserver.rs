@@ -2,11 +2,15 @@ use crate::log::{self, Log};
 use crate::state::{Config, State};
 use crate::{
     devices::{self, Devices},
+    discovery, groups, history, hosts, maintenance,
     state::User,
+    traceroute, transfer,
 };
 use parking_lot::Mutex;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde_json::{self, json};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::{convert::Infallible, str, time::Instant};
 use warp::{
@@ -18,7 +22,7 @@ use warp::{
     Filter, Rejection, Reply,
 };
 
-fn settings(state: &State) -> BoxedFilter<(impl Reply,)> {
+fn settings(state: &State, devices: &Arc<Devices>) -> BoxedFilter<(impl Reply,)> {
     let state_ = state.clone();
     let read_settings = warp::path("settings")
         .and(warp::get())
@@ -29,6 +33,7 @@ fn settings(state: &State) -> BoxedFilter<(impl Reply,)> {
         });
 
     let state_ = state.clone();
+    let devices_ = devices.clone();
     let write_settings = warp::path("settings")
         .and(warp::post())
         .and(warp::path::end())
@@ -37,8 +42,10 @@ fn settings(state: &State) -> BoxedFilter<(impl Reply,)> {
             if config.web_port != 0 && config.ping_interval != 0 {
                 let mut state = state_.lock();
                 state.config = config;
-                state.save();
-                ""
+                match devices_.storage.save_config(&state) {
+                    Ok(()) => "",
+                    Err(_) => "error",
+                }
             } else {
                 "error"
             }
@@ -122,11 +129,15 @@ fn login(state: State, sessions: Arc<Mutex<Vec<(String, Instant)>>>, user: User)
     }
 }
 
-pub async fn webserver(devices: Arc<Devices>, state: State, log: Arc<Log>) {
-    let port = state.lock().config.web_port;
-
-    let files = warp::fs::dir("web");
-    let index = warp::fs::file("web/index.html");
+pub async fn webserver(
+    devices: Arc<Devices>,
+    state: State,
+    log: Arc<Log>,
+    web_dir: PathBuf,
+    address: SocketAddr,
+) {
+    let files = warp::fs::dir(web_dir.clone());
+    let index = warp::fs::file(web_dir.join("index.html"));
 
     let app = files
         .or(index)
@@ -152,7 +163,16 @@ pub async fn webserver(devices: Arc<Devices>, state: State, log: Arc<Log>) {
         )
     });
 
-    let protected_api = settings(&state).or(devices::webserver(devices)).or(log);
+    let protected_api = settings(&state, &devices)
+        .or(devices::webserver(devices.clone()))
+        .or(groups::webserver(devices.clone()))
+        .or(discovery::webserver(devices.clone()))
+        .or(hosts::webserver(devices.clone()))
+        .or(maintenance::webserver(devices.clone()))
+        .or(history::webserver(devices.clone()))
+        .or(traceroute::webserver(devices.clone()))
+        .or(transfer::webserver(devices))
+        .or(log);
 
     let protected_api = protected(sessions).and(protected_api);
 
@@ -160,5 +180,5 @@ pub async fn webserver(devices: Arc<Devices>, state: State, log: Arc<Log>) {
 
     let api = warp::path("api").and(api.recover(api_error));
 
-    warp::serve(api.or(app)).run(([0, 0, 0, 0], port)).await;
+    warp::serve(api.or(app)).run(address).await;
 }
@@ -0,0 +1,139 @@
+use crate::devices::{Device, Devices};
+use crate::log::{Kind, Log};
+use crate::mac::MacAddr;
+use crate::state::Wol;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::spawn;
+use tokio::time::{delay_for, Duration};
+
+pub fn magic_packet(mac: MacAddr) -> Vec<u8> {
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac.0);
+    }
+    packet
+}
+
+fn send_magic_packet(conf: &Wol, mac: MacAddr) -> io::Result<()> {
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_broadcast(true)?;
+
+    if let Some(interface) = &conf.interface {
+        bind_interface(&socket, interface)?;
+    }
+
+    let addr = SocketAddrV4::new(conf.broadcast, conf.port);
+    socket.send_to(&magic_packet(mac), &addr.into())?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_interface(socket: &Socket, interface: &str) -> io::Result<()> {
+    let interface = std::ffi::CString::new(interface)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    socket.bind_device(Some(&interface))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_interface(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Binding to an interface is only supported on Linux",
+    ))
+}
+
+/// Sends a wake-on-LAN packet to the device and reports in the log whether it comes up.
+pub fn wake(devices: &Arc<Devices>, device: &Arc<Device>) -> bool {
+    let (desc, mac, monitored) = {
+        let conf = device.conf.lock();
+        (conf.desc(), conf.mac, conf.ipv4.is_some())
+    };
+
+    let mac = match mac {
+        Some(mac) => mac,
+        None => {
+            devices.log.log(
+                Kind::Error,
+                &format!("Unable to wake device {} without a MAC address", desc),
+            );
+            return false;
+        }
+    };
+
+    let conf = devices.conf.lock().wol.clone();
+
+    if let Err(error) = send_magic_packet(&conf, mac) {
+        devices.log.log(
+            Kind::Error,
+            &format!("Unable to send wake-on-LAN packet to {}\n{}", desc, error),
+        );
+        return false;
+    }
+
+    devices.log.note(&format!(
+        "Sent wake-on-LAN packet to device {} ({})",
+        desc, mac
+    ));
+
+    if monitored {
+        spawn(watch(
+            devices.log.clone(),
+            device.clone(),
+            desc,
+            conf.timeout,
+        ));
+    }
+
+    true
+}
+
+async fn watch(log: Arc<Log>, device: Arc<Device>, desc: String, secs: u64) {
+    let start = Instant::now();
+
+    loop {
+        let elapsed = Instant::now().saturating_duration_since(start);
+
+        if device.icmpv4.lock().status.map(|s| s.0.is_up()) == Some(true) {
+            log.note(&format!(
+                "Device {} is up {} seconds after wake-on-LAN",
+                desc,
+                elapsed.as_secs()
+            ));
+            return;
+        }
+
+        if elapsed.as_secs() >= secs {
+            log.log(
+                Kind::Error,
+                &format!(
+                    "Device {} did not come up within {} seconds of wake-on-LAN",
+                    desc, secs
+                ),
+            );
+            return;
+        }
+
+        delay_for(Duration::from_secs(1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet() {
+        let mac = MacAddr([0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0xef]);
+        let packet = magic_packet(mac);
+
+        assert_eq!(packet.len(), 6 + 16 * 6);
+        assert_eq!(packet[..6], [0xff; 6]);
+        for copy in packet[6..].chunks(6) {
+            assert_eq!(copy, mac.0);
+        }
+    }
+}